@@ -0,0 +1,154 @@
+//! Generates the `secret` module from the deployment secrets file, so secrets
+//! never live in the source tree.
+//!
+//! - `ECTF_SECRETS`: path to the file written by `tools/gen_secrets.py`.
+//! - `ECTF_COMPONENT_ID`: the component being built, for `component` builds.
+//! - `ECTF_AP_PIN`, `ECTF_AP_TOKEN`: the AP PIN and replacement token, for `ap`
+//!   builds. Only their hashes end up in the image.
+//!
+//! All are required for release builds. Development builds fall back to fixed,
+//! well known values.
+//!
+//! Release builds must also pick one of the `ap` and `component` features, or a
+//! component image would carry `DEPLOYMENT_SECRET`. Host builds (`std`) never
+//! leave the host and may enable both.
+
+#[path = "src/kdf.rs"]
+mod kdf;
+
+use kdf::{secret_hash, secret_salt, KEY_SIZE};
+use std::{env, fmt::Write, fs, path::PathBuf};
+
+const SECRETS_VAR: &str = "ECTF_SECRETS";
+const COMPONENT_ID_VAR: &str = "ECTF_COMPONENT_ID";
+const AP_PIN_VAR: &str = "ECTF_AP_PIN";
+const AP_TOKEN_VAR: &str = "ECTF_AP_TOKEN";
+
+const DEVELOPMENT_SECRET: [u8; KEY_SIZE] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15];
+const DEVELOPMENT_COMPONENT_ID: u32 = 0x11111124;
+const DEVELOPMENT_AP_PIN: &str = "123456";
+const DEVELOPMENT_AP_TOKEN: &str = "0123456789abcdef";
+
+fn main() {
+    println!("cargo:rerun-if-changed=src/kdf.rs");
+    println!("cargo:rerun-if-env-changed={SECRETS_VAR}");
+    println!("cargo:rerun-if-env-changed={COMPONENT_ID_VAR}");
+    println!("cargo:rerun-if-env-changed={AP_PIN_VAR}");
+    println!("cargo:rerun-if-env-changed={AP_TOKEN_VAR}");
+    let release = env::var("PROFILE").unwrap() == "release";
+    let ap = env::var_os("CARGO_FEATURE_AP").is_some();
+    let component = env::var_os("CARGO_FEATURE_COMPONENT").is_some();
+    let host = env::var_os("CARGO_FEATURE_STD").is_some();
+
+    if release && ap && component && !host {
+        panic!(
+            "release builds must enable only one of the `ap` and `component` features, \
+             use --no-default-features --features ap or --features component"
+        );
+    }
+
+    let deployment_secret = match env::var(SECRETS_VAR) {
+        Ok(path) => {
+            println!("cargo:rerun-if-changed={path}");
+            read_deployment_secret(&path)
+        }
+        Err(_) if release => panic!("{SECRETS_VAR} must be set for release builds"),
+        Err(_) => {
+            println!("cargo:warning={SECRETS_VAR} is not set, using the development secret");
+            DEVELOPMENT_SECRET
+        }
+    };
+
+    let mut module = String::new();
+    if ap {
+        writeln!(
+            module,
+            "/// Every component key is derived from this. Only the AP carries it, since it\n\
+             /// has to talk to any component it may be provisioned with.\n\
+             pub const DEPLOYMENT_SECRET: [u8; KEY_SIZE] = {deployment_secret:?};"
+        )
+        .unwrap();
+
+        // Host builds take the PIN and token at run time instead, see `host`.
+        if !host {
+            let pin = read_ap_secret(AP_PIN_VAR, DEVELOPMENT_AP_PIN, release);
+            let token = read_ap_secret(AP_TOKEN_VAR, DEVELOPMENT_AP_TOKEN, release);
+            let salt = secret_salt(&deployment_secret);
+            let pin_hash = secret_hash(&salt, pin.as_bytes());
+            let token_hash = secret_hash(&salt, token.as_bytes());
+            writeln!(
+                module,
+                "/// `secret_hash` of the AP PIN and replacement token.\n\
+                 pub const AP_PIN_HASH: [u8; HASH_SIZE] = {pin_hash:?};\n\
+                 pub const AP_TOKEN_HASH: [u8; HASH_SIZE] = {token_hash:?};"
+            )
+            .unwrap();
+        }
+    }
+    if component {
+        let component_id = match env::var(COMPONENT_ID_VAR) {
+            Ok(id) => parse_component_id(&id),
+            Err(_) if release => panic!("{COMPONENT_ID_VAR} must be set for release builds"),
+            Err(_) => DEVELOPMENT_COMPONENT_ID,
+        };
+        let component_key = kdf::component_key(&deployment_secret, component_id);
+        writeln!(
+            module,
+            "/// The id `COMPONENT_KEY` belongs to, checked against `ectf_params.h` at\n\
+             /// startup.\n\
+             pub const COMPONENT_ID: u32 = 0x{component_id:08x};\n\
+             /// This component's own key, `kdf::component_key(DEPLOYMENT_SECRET, COMPONENT_ID)`.\n\
+             pub const COMPONENT_KEY: [u8; KEY_SIZE] = {component_key:?};"
+        )
+        .unwrap();
+    }
+
+    let out_dir = PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    fs::write(out_dir.join("secret.rs"), module).unwrap();
+}
+
+/// The secrets file holds `name = hex` lines, `#` starts a comment.
+fn read_deployment_secret(path: &str) -> [u8; KEY_SIZE] {
+    let secrets = fs::read_to_string(path)
+        .unwrap_or_else(|err| panic!("could not read {SECRETS_VAR} ({path}): {err}"));
+
+    let hex = secrets
+        .lines()
+        .map(|line| line.split('#').next().unwrap().trim())
+        .filter_map(|line| line.split_once('='))
+        .find(|(name, _)| name.trim() == "deployment_secret")
+        .map(|(_, value)| value.trim())
+        .unwrap_or_else(|| panic!("{path} has no deployment_secret"));
+
+    let bytes = (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            hex.get(i..i + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+        })
+        .collect::<Option<Vec<u8>>>()
+        .unwrap_or_else(|| panic!("deployment_secret in {path} is not valid hex"));
+    bytes
+        .try_into()
+        .unwrap_or_else(|_| panic!("deployment_secret in {path} must be {KEY_SIZE} bytes"))
+}
+
+fn read_ap_secret(var: &str, development: &str, release: bool) -> String {
+    match env::var(var) {
+        Ok(secret) => secret,
+        Err(_) if release => panic!("{var} must be set for release builds"),
+        Err(_) => {
+            println!("cargo:warning={var} is not set, using the development value");
+            development.into()
+        }
+    }
+}
+
+fn parse_component_id(id: &str) -> u32 {
+    let id = id.trim();
+    match id.strip_prefix("0x").or_else(|| id.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => id.parse(),
+    }
+    .unwrap_or_else(|_| panic!("{COMPONENT_ID_VAR} is not a valid component id: {id}"))
+}
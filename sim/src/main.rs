@@ -0,0 +1,179 @@
+//! Runs an AP and its components on the host, connected by a simulated I2C
+//! bus. Commands are read from stdin and answered on stdout just like on the
+//! AP's UART, so the host tools can drive it. Components log to stderr.
+
+use std::{collections::BTreeMap, env, process::exit, thread};
+
+use ectf_2024::{
+    host::{self, DeviceKind},
+    mock::MockBus,
+};
+
+const USAGE: &str = "\
+usage: ectf_2024_sim [OPTIONS]
+
+    --pin PIN           AP PIN [default: 123456]
+    --token TOKEN       AP replacement token [default: 0123456789abcdef]
+    --component ID      provisioned component
+    --missing ID        provisioned component that isn't on the bus
+    --counterfeit ID    provisioned component that holds the wrong key
+    --spare ID          component on the bus that isn't provisioned, for replace
+    --help              print this message
+
+IDs are hex with or without a 0x prefix, or decimal with a 0n prefix, as on
+the AP. Without any components, 0x11111124 and 0x11111125 are provisioned.";
+
+const DEFAULT_COMPONENTS: [u32; 2] = [0x11111124, 0x11111125];
+
+#[derive(Clone, Copy, PartialEq)]
+enum Fault {
+    None,
+    Missing,
+    Counterfeit,
+}
+
+struct Component {
+    id: u32,
+    provisioned: bool,
+    fault: Fault,
+}
+
+impl Component {
+    fn device(&self) -> DeviceKind {
+        DeviceKind::Component {
+            id: self.id,
+            boot_msg: leak(format!("Component 0x{:08x} booted", self.id)),
+            attestation_loc: "McLean",
+            attestation_date: "08/08/08",
+            attestation_customer: leak(format!("Customer of 0x{:08x}", self.id)),
+        }
+    }
+}
+
+struct Config {
+    pin: String,
+    token: String,
+    components: Vec<Component>,
+}
+
+impl Config {
+    fn ap_device(&self) -> DeviceKind {
+        let comp_ids = self
+            .components
+            .iter()
+            .filter(|component| component.provisioned)
+            .map(|component| component.id)
+            .collect::<Vec<_>>();
+
+        DeviceKind::ApplicationProcessor {
+            pin_hash: host::secret_hash(self.pin.as_bytes()),
+            token_hash: host::secret_hash(self.token.as_bytes()),
+            boot_msg: "AP booted",
+            comp_ids: Vec::leak(comp_ids),
+        }
+    }
+}
+
+/// Every simulated device borrows its parameters for the whole run, just like
+/// the `ectf_params.h` strings on a board.
+fn leak(string: String) -> &'static str {
+    String::leak(string)
+}
+
+fn parse_id(arg: &str) -> Result<u32, String> {
+    host::component_id(arg.as_bytes()).map_err(|err| format!("{err}: '{arg}'"))
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Config, String> {
+    let mut config = Config {
+        pin: "123456".into(),
+        token: "0123456789abcdef".into(),
+        components: Vec::new(),
+    };
+
+    while let Some(flag) = args.next() {
+        if flag == "--help" {
+            println!("{USAGE}");
+            exit(0);
+        }
+        let value = args
+            .next()
+            .ok_or_else(|| format!("missing value for '{flag}'"))?;
+        let (provisioned, fault) = match flag.as_str() {
+            "--pin" => {
+                config.pin = value;
+                continue;
+            }
+            "--token" => {
+                config.token = value;
+                continue;
+            }
+            "--component" => (true, Fault::None),
+            "--missing" => (true, Fault::Missing),
+            "--counterfeit" => (true, Fault::Counterfeit),
+            "--spare" => (false, Fault::None),
+            _ => return Err(format!("unknown option '{flag}'")),
+        };
+        config.components.push(Component {
+            id: parse_id(&value)?,
+            provisioned,
+            fault,
+        });
+    }
+
+    if config.components.is_empty() {
+        config.components = DEFAULT_COMPONENTS
+            .into_iter()
+            .map(|id| Component {
+                id,
+                provisioned: true,
+                fault: Fault::None,
+            })
+            .collect();
+    }
+
+    Ok(config)
+}
+
+fn main() {
+    let config = parse_args(env::args().skip(1)).unwrap_or_else(|err| {
+        eprintln!("{err}\n\n{USAGE}");
+        exit(2);
+    });
+
+    let bus = MockBus::new();
+    let mut running = BTreeMap::new();
+    for component in &config.components {
+        if component.fault == Fault::Missing {
+            continue;
+        }
+
+        let device = component.device();
+        let i2c = host::attach(&bus, &device).unwrap_or_else(|err| {
+            eprintln!("component 0x{:08x}: {:?}", component.id, err);
+            exit(2);
+        });
+        let mut key = host::component_key(component.id);
+        if component.fault == Fault::Counterfeit {
+            key[0] ^= 1;
+        }
+
+        let handle = thread::spawn(move || host::component_main(i2c, device, &key));
+        running.insert(component.id, handle);
+    }
+
+    let booted = host::ap_main(&bus, config.ap_device()).unwrap_or_else(|err| {
+        eprintln!("AP: {err:?}");
+        exit(1);
+    });
+
+    // Let the booted components print their boot messages before exiting.
+    for id in booted {
+        if let Some(handle) = running.remove(id) {
+            _ = handle.join();
+        }
+    }
+
+    // The rest are still waiting on the bus and go down with the process.
+    exit(0);
+}
@@ -0,0 +1,79 @@
+//! Every component answers at the I2C address given by the low byte of its
+//! component ID. Addresses outside the 7-bit range, the ones reserved by the
+//! I2C spec, and the ones already taken on the MAX78000FTHR are rejected.
+
+use max78000_hal::error::{ErrorKind, Result};
+
+pub type ComponentId = u32;
+
+/// 0x00..=0x07 and 0x78..=0x7F are reserved by the I2C spec.
+const FIRST_ADDRESS: u8 = 0x08;
+const LAST_ADDRESS: u8 = 0x77;
+
+/// 0x18, 0x28, and 0x36 conflict with separate devices on MAX78000FTHR.
+const BLACKLIST: [u8; 3] = [0x18, 0x28, 0x36];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct I2cAddress(u8);
+
+impl I2cAddress {
+    /// Fails with `ErrorKind::BadParam` for reserved or blacklisted addresses.
+    pub fn new(address: u8) -> Result<Self> {
+        if !(FIRST_ADDRESS..=LAST_ADDRESS).contains(&address) || BLACKLIST.contains(&address) {
+            return Err(ErrorKind::BadParam);
+        }
+        Ok(Self(address))
+    }
+}
+
+impl TryFrom<ComponentId> for I2cAddress {
+    type Error = ErrorKind;
+
+    fn try_from(component_id: ComponentId) -> Result<Self> {
+        Self::new(component_id as u8)
+    }
+}
+
+impl From<I2cAddress> for u8 {
+    fn from(address: I2cAddress) -> Self {
+        address.0
+    }
+}
+
+impl From<I2cAddress> for usize {
+    fn from(address: I2cAddress) -> Self {
+        address.0 as usize
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_address_is_low_byte_of_id() {
+        assert!(matches!(
+            I2cAddress::try_from(0x11111124),
+            Ok(I2cAddress(0x24))
+        ));
+        assert_eq!(u8::from(I2cAddress::try_from(0xdeadbe77).unwrap()), 0x77);
+        assert_eq!(usize::from(I2cAddress::try_from(0x00000008).unwrap()), 0x08);
+    }
+
+    #[test]
+    fn test_reserved_addresses_are_rejected() {
+        for address in (0x00..FIRST_ADDRESS).chain(LAST_ADDRESS + 1..=u8::MAX) {
+            assert!(matches!(I2cAddress::new(address), Err(ErrorKind::BadParam)));
+        }
+    }
+
+    #[test]
+    fn test_blacklisted_addresses_are_rejected() {
+        for address in BLACKLIST {
+            assert!(matches!(
+                I2cAddress::try_from(0x11111100 | address as ComponentId),
+                Err(ErrorKind::BadParam)
+            ));
+        }
+    }
+}
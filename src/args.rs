@@ -0,0 +1,210 @@
+//! Validates the arguments host commands take. Nothing here panics on bad
+//! input, every problem comes back as an `ArgError` for the host to see.
+
+use core::{fmt, str::from_utf8};
+
+use crate::{host_msg, host_msg::read_arg};
+
+pub const PIN_LEN: usize = 6;
+pub const TOKEN_LEN: usize = 16;
+/// `0n` and the 10 decimal digits of `u32::MAX`.
+pub const MAX_COMPONENT_ID_LEN: usize = 12;
+
+/// An argument a command takes, see `commands`.
+#[derive(Clone, Copy)]
+pub enum Spec {
+    Pin,
+    Token,
+    ComponentId,
+}
+
+/// An argument read and validated according to its `Spec`.
+#[derive(Clone, Copy)]
+pub enum Value {
+    Secret(Secret),
+    ComponentId(u32),
+}
+
+/// A PIN or token, hex digits only.
+#[derive(Clone, Copy)]
+pub struct Secret {
+    len: u8,
+    digits: [u8; TOKEN_LEN],
+}
+
+impl Secret {
+    fn new(secret: &str) -> Self {
+        let mut digits = [0u8; TOKEN_LEN];
+        let len = secret.len().min(TOKEN_LEN);
+        digits[..len].copy_from_slice(&secret.as_bytes()[..len]);
+        Self {
+            len: len as u8,
+            digits,
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        from_utf8(&self.digits[..self.len as usize]).unwrap_or_default()
+    }
+}
+
+impl Spec {
+    /// How the argument shows up in help text.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Spec::Pin => "PIN",
+            Spec::Token => "TOKEN",
+            Spec::ComponentId => "ID",
+        }
+    }
+
+    fn max_len(&self) -> usize {
+        match self {
+            Spec::Pin => PIN_LEN,
+            Spec::Token => TOKEN_LEN,
+            Spec::ComponentId => MAX_COMPONENT_ID_LEN,
+        }
+    }
+
+    /// Acknowledges the previous input, then reads this argument.
+    pub fn read(&self) -> Result<Value, ArgError> {
+        let mut buffer = [0u8; TOKEN_LEN];
+        host_msg!(Ack);
+        let arg = read(&mut buffer[..self.max_len()])?;
+        Ok(match self {
+            Spec::Pin => Value::Secret(Secret::new(pin(arg)?)),
+            Spec::Token => Value::Secret(Secret::new(token(arg)?)),
+            Spec::ComponentId => Value::ComponentId(component_id(arg)?),
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum ArgError {
+    Missing,
+    /// Longer than the buffer it was read into, so longer than any valid value.
+    TooLong,
+    ComponentId,
+    Pin,
+    Token,
+}
+
+impl fmt::Display for ArgError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            ArgError::Missing => "Missing argument",
+            ArgError::TooLong => "Argument too long",
+            ArgError::ComponentId => {
+                "Invalid component ID, expected hex digits with or without 0x, or 0n and decimal digits"
+            }
+            ArgError::Pin => "Invalid PIN",
+            ArgError::Token => "Invalid token",
+        })
+    }
+}
+
+/// Reads the next argument from the host into `buffer`. Fails with
+/// `ArgError::TooLong` rather than cutting it short.
+pub fn read(buffer: &mut [u8]) -> Result<&[u8], ArgError> {
+    let len = read_arg(buffer);
+    buffer.get(..len).ok_or(ArgError::TooLong)
+}
+
+/// Hex, with or without a `0x` prefix, or decimal with a `0n` prefix. Bare
+/// digits are hex since that is how component IDs are written everywhere else,
+/// so decimal needs a prefix of its own.
+pub fn component_id(arg: &[u8]) -> Result<u32, ArgError> {
+    let (digits, radix) = match arg {
+        [] => return Err(ArgError::Missing),
+        [b'0', b'x' | b'X', digits @ ..] => (digits, 16),
+        [b'0', b'n' | b'N', digits @ ..] => (digits, 10),
+        digits => (digits, 16),
+    };
+    // `from_str_radix` takes a sign, we don't.
+    if digits.is_empty() || !digits.iter().all(|digit| (*digit as char).is_digit(radix)) {
+        return Err(ArgError::ComponentId);
+    }
+    from_utf8(digits)
+        .ok()
+        .and_then(|digits| u32::from_str_radix(digits, radix).ok())
+        .ok_or(ArgError::ComponentId)
+}
+
+/// Exactly `PIN_LEN` hex digits.
+pub fn pin(arg: &[u8]) -> Result<&str, ArgError> {
+    hex_secret(arg, PIN_LEN, ArgError::Pin)
+}
+
+/// Exactly `TOKEN_LEN` hex digits.
+pub fn token(arg: &[u8]) -> Result<&str, ArgError> {
+    hex_secret(arg, TOKEN_LEN, ArgError::Token)
+}
+
+fn hex_secret(arg: &[u8], len: usize, err: ArgError) -> Result<&str, ArgError> {
+    match arg {
+        [] => Err(ArgError::Missing),
+        arg if arg.len() == len && arg.iter().all(u8::is_ascii_hexdigit) => {
+            from_utf8(arg).map_err(|_| err)
+        }
+        _ => Err(err),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_component_id() {
+        assert!(matches!(component_id(b"0x11111124"), Ok(0x11111124)));
+        assert!(matches!(component_id(b"0XABCDEF01"), Ok(0xabcdef01)));
+        assert!(matches!(component_id(b"0x1"), Ok(1)));
+        assert!(matches!(component_id(b"0xffffffff"), Ok(u32::MAX)));
+    }
+
+    #[test]
+    fn test_component_id_without_prefix_is_hex() {
+        assert!(matches!(component_id(b"11111124"), Ok(0x11111124)));
+        assert!(matches!(component_id(b"1111112a"), Ok(0x1111112a)));
+        assert!(matches!(component_id(b"0"), Ok(0)));
+    }
+
+    #[test]
+    fn test_decimal_component_id() {
+        assert!(matches!(component_id(b"0n286331172"), Ok(0x11111124)));
+        assert!(matches!(component_id(b"0N4294967295"), Ok(u32::MAX)));
+        for arg in [&b"0n"[..], b"0n4294967296", b"0n1a", b"0n+1", b"0x0n1"] {
+            assert!(matches!(component_id(arg), Err(ArgError::ComponentId)));
+        }
+    }
+
+    #[test]
+    fn test_malformed_component_id_is_rejected() {
+        for arg in [
+            &b"0x"[..],
+            b"zz",
+            b"0x+1",
+            b"+1",
+            b"-1",
+            b"0x100000000",
+            b"100000000",
+            b"0x1111 124",
+            b"\xff\xfe",
+        ] {
+            assert!(matches!(component_id(arg), Err(ArgError::ComponentId)));
+        }
+        assert!(matches!(component_id(b""), Err(ArgError::Missing)));
+    }
+
+    #[test]
+    fn test_secrets() {
+        assert!(matches!(pin(b"123abc"), Ok("123abc")));
+        assert!(matches!(token(b"0123456789abcdef"), Ok("0123456789abcdef")));
+
+        for arg in [&b"12345"[..], b"1234567", b"12345g", b"\xff23456"] {
+            assert!(matches!(pin(arg), Err(ArgError::Pin)));
+        }
+        assert!(matches!(token(b"0123456789abcde"), Err(ArgError::Token)));
+        assert!(matches!(pin(b""), Err(ArgError::Missing)));
+    }
+}
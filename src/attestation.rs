@@ -0,0 +1,167 @@
+//! Attestation data travels as one record, `len || location || len || date ||
+//! len || customer`, split into as many `Attest` responses as it needs. Every
+//! response starts with the total record length so the AP knows when it has
+//! all of it.
+
+use crate::security::MAX_TRANSACTION_SIZE;
+#[cfg(feature = "ap")]
+use max78000_hal::error::{ErrorKind, Result};
+
+const TOTAL_LEN_SIZE: usize = 2;
+const CHUNK_SIZE: usize = MAX_TRANSACTION_SIZE - TOTAL_LEN_SIZE;
+const FIELD_COUNT: usize = 3;
+#[cfg(feature = "ap")]
+pub const MAX_RECORD_SIZE: usize = FIELD_COUNT * (1 + u8::MAX as usize);
+
+pub struct Attestation<'a> {
+    pub location: &'a str,
+    pub date: &'a str,
+    pub customer: &'a str,
+}
+
+#[cfg(feature = "component")]
+impl<'a> Attestation<'a> {
+    fn fields(&self) -> [&'a [u8]; FIELD_COUNT] {
+        [self.location, self.date, self.customer].map(|field| {
+            let field = field.as_bytes();
+            &field[..field.len().min(u8::MAX as usize)]
+        })
+    }
+
+    fn record(&self) -> impl Iterator<Item = u8> + 'a {
+        self.fields()
+            .into_iter()
+            .flat_map(|field| [field.len() as u8].into_iter().chain(field.iter().copied()))
+    }
+
+    /// The `index`th `Attest` response.
+    pub fn chunk(&self, index: u8) -> [u8; MAX_TRANSACTION_SIZE] {
+        let total_len = self.record().count() as u16;
+
+        let mut chunk = [0u8; MAX_TRANSACTION_SIZE];
+        chunk[..TOTAL_LEN_SIZE].copy_from_slice(&total_len.to_le_bytes());
+        chunk[TOTAL_LEN_SIZE..]
+            .iter_mut()
+            .zip(self.record().skip(index as usize * CHUNK_SIZE))
+            .for_each(|(chunk, byte)| *chunk = byte);
+        chunk
+    }
+}
+
+/// Collects the `Attest` responses on the AP.
+#[cfg(feature = "ap")]
+pub struct AttestationRecord {
+    data: [u8; MAX_RECORD_SIZE],
+    total_len: Option<usize>,
+    received: usize,
+}
+
+#[cfg(feature = "ap")]
+impl AttestationRecord {
+    pub const fn new() -> Self {
+        Self {
+            data: [0u8; MAX_RECORD_SIZE],
+            total_len: None,
+            received: 0,
+        }
+    }
+
+    /// Index of the next chunk to request, or `None` once the record is
+    /// complete.
+    pub fn next_index(&self) -> Option<u8> {
+        match self.total_len {
+            Some(total_len) if self.received >= total_len => None,
+            _ => Some((self.received / CHUNK_SIZE) as u8),
+        }
+    }
+
+    /// Fails with `ErrorKind::Overflow` if the component claims a record
+    /// larger than any it could send, and `ErrorKind::BadState` if it changes
+    /// its claim halfway through.
+    pub fn add_chunk(&mut self, chunk: &[u8; MAX_TRANSACTION_SIZE]) -> Result<()> {
+        let total_len = u16::from_le_bytes([chunk[0], chunk[1]]) as usize;
+        if total_len > MAX_RECORD_SIZE {
+            return Err(ErrorKind::Overflow);
+        }
+        if *self.total_len.get_or_insert(total_len) != total_len {
+            return Err(ErrorKind::BadState);
+        }
+
+        let len = (total_len - self.received).min(CHUNK_SIZE);
+        self.data[self.received..self.received + len]
+            .copy_from_slice(&chunk[TOTAL_LEN_SIZE..TOTAL_LEN_SIZE + len]);
+        self.received += len;
+        Ok(())
+    }
+
+    /// Splits the complete record back into its fields. Fails with
+    /// `ErrorKind::BadParam` if the record is malformed.
+    pub fn attestation(&self) -> Result<Attestation<'_>> {
+        let mut record = &self.data[..self.total_len.ok_or(ErrorKind::Uninitialized)?];
+        let mut fields = [""; FIELD_COUNT];
+        for field in fields.iter_mut() {
+            let (len, rest) = record.split_first().ok_or(ErrorKind::BadParam)?;
+            let (bytes, rest) = rest
+                .split_at_checked(*len as usize)
+                .ok_or(ErrorKind::BadParam)?;
+            *field = core::str::from_utf8(bytes).map_err(|_| ErrorKind::BadParam)?;
+            record = rest;
+        }
+        if !record.is_empty() {
+            return Err(ErrorKind::BadParam);
+        }
+
+        let [location, date, customer] = fields;
+        Ok(Attestation {
+            location,
+            date,
+            customer,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn round_trip(attestation: &Attestation) {
+        let mut record = AttestationRecord::new();
+        while let Some(index) = record.next_index() {
+            record.add_chunk(&attestation.chunk(index)).unwrap();
+        }
+
+        let received = record.attestation().unwrap();
+        assert_eq!(received.location, attestation.location);
+        assert_eq!(received.date, attestation.date);
+        assert_eq!(received.customer, attestation.customer);
+    }
+
+    #[test]
+    fn test_single_chunk_round_trip() {
+        round_trip(&Attestation {
+            location: "McLean",
+            date: "08/08/08",
+            customer: "Fritz",
+        });
+    }
+
+    #[test]
+    fn test_multi_chunk_round_trip() {
+        let long = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+        round_trip(&Attestation {
+            location: long,
+            date: long,
+            customer: "",
+        });
+    }
+
+    #[test]
+    fn test_oversized_record_is_rejected() {
+        let mut chunk = [0u8; MAX_TRANSACTION_SIZE];
+        chunk[..TOTAL_LEN_SIZE].copy_from_slice(&(MAX_RECORD_SIZE as u16 + 1).to_le_bytes());
+        assert!(matches!(
+            AttestationRecord::new().add_chunk(&chunk),
+            Err(ErrorKind::Overflow)
+        ));
+    }
+}
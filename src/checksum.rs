@@ -0,0 +1,61 @@
+//! CRC-16/CCITT-FALSE over every frame on the bus, so bits flipped in transit
+//! are told apart from frames forged without the key. Anyone can recompute it,
+//! so it never stands in for a tag.
+
+use max78000_hal::error::{ErrorKind, Result};
+
+pub const CHECKSUM_SIZE: usize = 2;
+
+const POLYNOMIAL: u16 = 0x1021;
+
+pub fn checksum(bytes: impl IntoIterator<Item = u8>) -> [u8; CHECKSUM_SIZE] {
+    bytes
+        .into_iter()
+        .fold(0xffffu16, |crc, byte| {
+            (0..8).fold(crc ^ ((byte as u16) << 8), |crc, _| match crc & 0x8000 {
+                0 => crc << 1,
+                _ => (crc << 1) ^ POLYNOMIAL,
+            })
+        })
+        .to_be_bytes()
+}
+
+/// Splits the checksum off the end of `frame` and returns the rest.
+///
+/// Fails with `ErrorKind::ComError` if the frame was corrupted on the bus.
+pub fn verify(frame: &mut [u8]) -> Result<&mut [u8]> {
+    let split = frame
+        .len()
+        .checked_sub(CHECKSUM_SIZE)
+        .ok_or(ErrorKind::Underflow)?;
+    let (frame, expected) = frame.split_at_mut(split);
+    match checksum(frame.iter().copied()) == *expected {
+        true => Ok(frame),
+        false => Err(ErrorKind::ComError),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_checksum() {
+        assert_eq!(checksum(*b"123456789"), [0x29, 0xb1]);
+    }
+
+    #[test]
+    fn test_corrupted_frame_is_rejected() {
+        let mut frame = [0u8; 16 + CHECKSUM_SIZE];
+        frame[..16].copy_from_slice(b"sixteen bytes!!!");
+        let sum = checksum(frame[..16].iter().copied());
+        frame[16..].copy_from_slice(&sum);
+        assert_eq!(verify(&mut frame.clone()).unwrap(), b"sixteen bytes!!!");
+
+        for bit in 0..frame.len() * 8 {
+            let mut corrupted = frame;
+            corrupted[bit / 8] ^= 1 << (bit % 8);
+            assert!(matches!(verify(&mut corrupted), Err(ErrorKind::ComError)));
+        }
+    }
+}
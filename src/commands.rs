@@ -1,19 +1,220 @@
-use core::str::from_utf8_unchecked;
+use core::{fmt, str::from_utf8};
 
 use crate::{
+    addressing::I2cAddress,
+    args::{self, Spec, Value},
+    attestation::AttestationRecord,
+    constant_time,
     ectf_params::{get_device, DeviceKind},
-    flash, host_msg,
-    host_msg::read_arg,
-    security::{secure_master_transaction, TransactionKind, MAX_TRANSACTION_SIZE},
-};
-use max78000_hal::{
-    aes::AES,
-    error::ErrorKind,
-    i2c::{I2CPort1, I2C},
-    trng::TRNG,
+    flash,
+    hal::{BlockCipher, Clock, I2cMaster, Rng},
+    host_msg,
+    kdf::HASH_SIZE,
+    messages::{AttestRequest, BootRequest, BootResponse, ListRequest, ListResponse, Request},
+    secret::{component_key, secret_hash},
+    security::{retried_master_transaction, MasterError, Retried},
 };
+use max78000_hal::error::ErrorKind;
+
+/// How long a wrong PIN or token stalls the AP, see `check_secret`.
+const LOCKOUT_DELAY_US: u32 = 5_000_000;
+
+/// This boot already sat out the delay for the last wrong guess.
+static mut LOCKOUT_SERVED: bool = false;
+
+/// Longer than any command name, see `commands`.
+const MAX_COMMAND_LEN: usize = 16;
+
+/// Most arguments any command takes.
+const MAX_ARGS: usize = 3;
+
+/// A command the host can send, matched against `name` exactly.
+struct Command<B, C, R, K> {
+    name: &'static str,
+    /// Acknowledged, read and validated in order before `handler` runs, which
+    /// gets a `Value` of the matching kind for each.
+    args: &'static [Spec],
+    help: &'static str,
+    /// Returns `true` once the AP should stop serving commands.
+    handler: fn(&mut B, &mut C, &mut R, &mut K, &[Value]) -> bool,
+}
+
+impl<B, C, R, K> fmt::Display for Command<B, C, R, K> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name)?;
+        for arg in self.args {
+            write!(f, " {}", arg.name())?;
+        }
+        write!(f, ": {}", self.help)
+    }
+}
+
+/// Every command `run` serves. New ones only need an entry here.
+fn commands<B: I2cMaster, C: BlockCipher, R: Rng, K: Clock>() -> [Command<B, C, R, K>; 5] {
+    [
+        Command {
+            name: "list",
+            args: &[],
+            help: "list provisioned and found components",
+            handler: |i2c, aes, trng, clock, _| {
+                list_cmd(i2c, aes, trng, clock);
+                false
+            },
+        },
+        Command {
+            name: "boot",
+            args: &[],
+            help: "verify and boot every component, then the AP",
+            handler: |i2c, aes, trng, clock, _| boot_cmd(i2c, aes, trng, clock),
+        },
+        Command {
+            name: "replace",
+            args: &[Spec::Token, Spec::ComponentId, Spec::ComponentId],
+            help: "provision the first component in place of the second",
+            handler: |_, _, _, clock, args| {
+                let [Value::Secret(token), Value::ComponentId(id_new), Value::ComponentId(id_old)] =
+                    args
+                else {
+                    unreachable!("args match the spec")
+                };
+                replace_cmd(clock, token.as_str(), *id_new, *id_old);
+                false
+            },
+        },
+        Command {
+            name: "attest",
+            args: &[Spec::Pin, Spec::ComponentId],
+            help: "print the attestation data of a component",
+            handler: |i2c, aes, trng, clock, args| {
+                let [Value::Secret(pin), Value::ComponentId(component)] = args else {
+                    unreachable!("args match the spec")
+                };
+                attest_cmd(i2c, aes, trng, clock, pin.as_str(), *component);
+                false
+            },
+        },
+        Command {
+            name: "help",
+            args: &[],
+            help: "list the commands",
+            handler: |_, _, _, _, _| {
+                for command in commands::<B, C, R, K>() {
+                    host_msg!(Info, "{}", command);
+                }
+                host_msg!(Success, "Help");
+                false
+            },
+        },
+    ]
+}
+
+/// Serves commands from the host until `boot` succeeds.
+pub fn run<B: I2cMaster, C: BlockCipher, R: Rng, K: Clock>(
+    i2c: &mut B,
+    aes: &mut C,
+    trng: &mut R,
+    clock: &mut K,
+) {
+    let commands = commands::<B, C, R, K>();
+    loop {
+        host_msg!(Debug, "Enter Command: ");
+        let mut name_buffer = [0; MAX_COMMAND_LEN];
+        let name = args::read(&mut name_buffer);
+        let Some(command) = name.ok().and_then(|name| {
+            commands
+                .iter()
+                .find(|command| command.name.as_bytes() == name)
+        }) else {
+            host_msg!(
+                Error,
+                "Unrecognized command '{}', try 'help'",
+                name.ok()
+                    .and_then(|name| from_utf8(name).ok())
+                    .unwrap_or("?")
+            );
+            continue;
+        };
+
+        // Read every argument before checking any, so the host isn't left
+        // sending the rest of them as commands.
+        let mut values = [Value::ComponentId(0); MAX_ARGS];
+        let mut parsed = Ok(());
+        for (spec, value) in command.args.iter().zip(&mut values) {
+            match spec.read() {
+                Ok(read) => *value = read,
+                Err(err) => parsed = parsed.and(Err(err)),
+            }
+        }
+        if let Err(err) = parsed {
+            host_msg!(Error, "{}", err);
+            continue;
+        }
+
+        if (command.handler)(i2c, aes, trng, clock, &values[..command.args.len()]) {
+            return;
+        }
+    }
+}
+
+/// Sends `request` to the component `component_id`, see
+/// `retried_master_transaction`, and logs how many retries it took. A
+/// component that speaks no protocol version we do is reported here, and
+/// fails with `ErrorKind::NotSupported`.
+fn transact<B: I2cMaster, C: BlockCipher, R: Rng, K: Clock, Req: Request>(
+    i2c: &mut B,
+    aes: &mut C,
+    trng: &mut R,
+    clock: &mut K,
+    component_id: u32,
+    request: &Req,
+) -> Result<Req::Response, ErrorKind> {
+    let address = I2cAddress::try_from(component_id)?;
+    let key = component_key(component_id);
+    let Retried { result, retries } =
+        retried_master_transaction(i2c, aes, trng, clock, address.into(), &key, request);
+    if retries > 0 {
+        host_msg!(Debug, "0x{:08x}: {} retries", component_id, retries);
+    }
+    result.map_err(|err| {
+        if let MasterError::Incompatible { ours, slave } = err {
+            host_msg!(
+                Error,
+                "0x{:08x} speaks protocol versions {}..={}, we speak {}..={}",
+                component_id,
+                slave.min,
+                slave.max,
+                ours.min,
+                ours.max
+            );
+        }
+        err.into()
+    })
+}
 
-pub fn list_cmd(i2c: &mut I2C<I2CPort1>, aes: &mut AES, trng: &mut TRNG) {
+/// Fails with `ErrorKind::BadState` if the component provisioned as
+/// `component_id` reports another id. One that doesn't report any has still
+/// proven it holds the key of `component_id`.
+fn check_id(component_id: u32, response: ListResponse) -> Result<(), ErrorKind> {
+    match response.id {
+        Some(id) if id != component_id => {
+            host_msg!(
+                Error,
+                "0x{:08x} reports itself as 0x{:08x}",
+                component_id,
+                id
+            );
+            Err(ErrorKind::BadState)
+        }
+        _ => Ok(()),
+    }
+}
+
+pub fn list_cmd<B: I2cMaster, C: BlockCipher, R: Rng, K: Clock>(
+    i2c: &mut B,
+    aes: &mut C,
+    trng: &mut R,
+    clock: &mut K,
+) {
     for component_id in match flash::get_component_ids() {
         Ok(ids) => ids,
         Err(e) => {
@@ -24,37 +225,22 @@ pub fn list_cmd(i2c: &mut I2C<I2CPort1>, aes: &mut AES, trng: &mut TRNG) {
         host_msg!(Info, "P>0x{:08x}", component_id);
     }
 
-    /*
-    for i2c_address in 0x8..0x78 {
-        // I2C Blacklist:
-        // 0x18, 0x28, and 0x36 conflict with separate devices on MAX78000FTHR
-        if i2c_address == 0x18 || i2c_address == 0x28 || i2c_address == 0x36 {
-            continue;
-        }
-        match secure_master_transaction(i2c, aes, trng, i2c_address, TransactionKind::List) {
-            Ok(_) => host_msg!(Info, "F>0x{:08x}", i2c_address),
-            Err(ErrorKind::ComError) => (),
-            Err(err) => host_msg!(Error, "{:?}", err),
-        }
-    }
-    */
-
-    for i2c_address in match flash::get_component_ids() {
+    for component_id in match flash::get_component_ids() {
         Ok(ids) => ids,
         Err(e) => {
             host_msg!(Error, "Flash {:?}", e);
             return;
         }
     } {
-        match secure_master_transaction(
-            i2c,
-            aes,
-            trng,
-            *i2c_address as usize,
-            TransactionKind::List,
-        ) {
-            Ok(_) => host_msg!(Info, "F>0x{:08x}", i2c_address),
+        match transact(i2c, aes, trng, clock, *component_id, &ListRequest) {
+            Ok(response) => {
+                host_msg!(Info, "F>0x{:08x}", response.id.unwrap_or(*component_id));
+                _ = check_id(*component_id, response);
+            }
+            // Still no answer after retrying, the component isn't there.
             Err(ErrorKind::ComError) => (),
+            // Already reported by `transact`.
+            Err(ErrorKind::NotSupported) => (),
             Err(err) => host_msg!(Error, "{:?}", err),
         }
     }
@@ -62,85 +248,146 @@ pub fn list_cmd(i2c: &mut I2C<I2CPort1>, aes: &mut AES, trng: &mut TRNG) {
     host_msg!(Success, "List");
 }
 
-pub fn boot_cmd(mut i2c: I2C<I2CPort1>, mut aes: AES, mut trng: TRNG) -> ! {
+/// Sends `request` to each of `component_ids` in turn, stopping at the first
+/// one that fails to authenticate or whose response `check` rejects.
+fn transact_all<B, C, R, K, Req, Check>(
+    i2c: &mut B,
+    aes: &mut C,
+    trng: &mut R,
+    clock: &mut K,
+    component_ids: &[u32],
+    request: &Req,
+    mut check: Check,
+) -> Result<(), (u32, ErrorKind)>
+where
+    B: I2cMaster,
+    C: BlockCipher,
+    R: Rng,
+    K: Clock,
+    Req: Request,
+    Check: FnMut(u32, Req::Response) -> Result<(), ErrorKind>,
+{
+    for component_id in component_ids {
+        transact(i2c, aes, trng, clock, *component_id, request)
+            .and_then(|response| check(*component_id, response))
+            .map_err(|err| (*component_id, err))?;
+    }
+    Ok(())
+}
+
+fn log_boot(component_id: u32, response: BootResponse) -> Result<(), ErrorKind> {
+    host_msg!(
+        Debug,
+        "0x{:08x} booted: {}",
+        component_id,
+        from_utf8(response.message()).unwrap_or("?")
+    );
+    Ok(())
+}
+
+/// Returns `true` once every provisioned component has been verified and
+/// booted, at which point the caller should hand over to the AP application.
+pub fn boot_cmd<B: I2cMaster, C: BlockCipher, R: Rng, K: Clock>(
+    i2c: &mut B,
+    aes: &mut C,
+    trng: &mut R,
+    clock: &mut K,
+) -> bool {
     let boot_msg = match get_device() {
         DeviceKind::ApplicationProcessor { boot_msg, .. } => boot_msg,
+        #[allow(unreachable_patterns)]
         _ => unreachable!("boot_cmd() is only called by ap"),
     };
 
-    for component_id in match flash::get_component_ids() {
+    let component_ids = match flash::get_component_ids() {
         Ok(ids) => ids,
         Err(e) => {
             host_msg!(Error, "Flash {:?}", e);
-            panic!("Flash {:?}", e);
+            return false;
         }
-    } {
-        let rx = match secure_master_transaction(
-            &mut i2c,
-            &mut aes,
-            &mut trng,
-            // component_id.clone() as usize,
-            0x00000023usize,
-            TransactionKind::Boot,
-        ) {
-            Ok(rx) => rx,
-            Err(err) => {
-                panic!("{:?}", err);
-            }
-        };
-        assert_eq!(rx, [1; MAX_TRANSACTION_SIZE]);
-        break;
+    };
+
+    // Authenticate everything before telling anyone to boot, so a bad
+    // component is caught while the whole system is still in its pre-boot
+    // state.
+    if let Err((component_id, err)) =
+        transact_all(i2c, aes, trng, clock, component_ids, &ListRequest, check_id)
+    {
+        host_msg!(Error, "Boot failed on 0x{:08x}: {:?}", component_id, err);
+        return false;
     }
 
-    _ = (i2c, aes, trng);
-    // TODO: move all held refs to statics for our c handlers to use
+    // A component can still drop out once the others are booting, and those
+    // can't be taken back. The failing one may have booted too, if only its
+    // response got lost, so all we know is which ones confirmed.
+    if let Err((component_id, err)) =
+        transact_all(i2c, aes, trng, clock, component_ids, &BootRequest, log_boot)
+    {
+        let booted = component_ids
+            .iter()
+            .take_while(|id| **id != component_id)
+            .count();
+        host_msg!(
+            Error,
+            "Boot failed on 0x{:08x}: {:?}, its state is unknown, {} of {} components confirmed booted",
+            component_id,
+            err,
+            booted,
+            component_ids.len()
+        );
+        return false;
+    }
 
     host_msg!(Info, "AP>{}", boot_msg);
     host_msg!(Success, "Boot");
-
-    unsafe { boot() }
+    true
 }
 
-extern "C" {
-    fn boot() -> !;
+/// Checks a PIN or token from the host against the `expected` hash, see
+/// `secret_hash`.
+///
+/// Every attempt counts as failed in flash until it is known to be right, and
+/// a wrong one stalls for `LOCKOUT_DELAY_US` before it is reported. Cutting the
+/// power to skip the delay only moves it in front of the next attempt.
+fn check_secret<K: Clock>(
+    clock: &mut K,
+    guess: &str,
+    expected: &[u8; HASH_SIZE],
+) -> Result<bool, ErrorKind> {
+    let failed_attempts = flash::get_failed_attempts()?;
+    if failed_attempts > 0 && !unsafe { LOCKOUT_SERVED } {
+        clock.delay_us(LOCKOUT_DELAY_US);
+        unsafe { LOCKOUT_SERVED = true };
+    }
+
+    flash::set_failed_attempts(failed_attempts.saturating_add(1))?;
+    if !constant_time::eq(expected, &secret_hash(guess.as_bytes())) {
+        host_msg!(Debug, "{} failed attempts", failed_attempts + 1);
+        clock.delay_us(LOCKOUT_DELAY_US);
+        unsafe { LOCKOUT_SERVED = true };
+        return Ok(false);
+    }
+
+    flash::set_failed_attempts(0)?;
+    Ok(true)
 }
 
-pub fn replace_cmd() {
-    host_msg!(Ack);
-
-    let mut token_buffer = [0; 16];
-    let mut id_new_buffer = [0; 16];
-    let mut id_old_buffer = [0; 16];
-
-    let (token, id_new, id_old) = {
-        let token_len = read_arg(&mut token_buffer);
-        host_msg!(Ack);
-        let id_new_len = read_arg(&mut id_new_buffer);
-        host_msg!(Ack);
-        let id_old_len = read_arg(&mut id_old_buffer);
-        (
-            unsafe { from_utf8_unchecked(&token_buffer[..token_len]) },
-            u32::from_str_radix(
-                &unsafe { from_utf8_unchecked(&id_new_buffer) }[2..id_new_len],
-                16,
-            )
-            .unwrap(),
-            u32::from_str_radix(
-                &unsafe { from_utf8_unchecked(&id_old_buffer) }[2..id_old_len],
-                16,
-            )
-            .unwrap(),
-        )
+pub fn replace_cmd<K: Clock>(clock: &mut K, token: &str, id_new: u32, id_old: u32) {
+    let token_hash = match get_device() {
+        DeviceKind::ApplicationProcessor { token_hash, .. } => token_hash,
+        #[allow(unreachable_patterns)]
+        _ => unreachable!("boot_cmd() is only called by ap"),
     };
-
-    if token
-        != match get_device() {
-            DeviceKind::ApplicationProcessor { ap_token, .. } => ap_token,
-            _ => unreachable!("boot_cmd() is only called by ap"),
+    match check_secret(clock, token, &token_hash) {
+        Ok(true) => (),
+        Ok(false) => {
+            host_msg!(Error, "Incorrect Token");
+            return;
+        }
+        Err(e) => {
+            host_msg!(Error, "Flash {:?}", e);
+            return;
         }
-    {
-        host_msg!(Error, "Incorrect Token");
-        return;
     }
 
     match flash::swap_component(id_old, id_new) {
@@ -150,44 +397,208 @@ pub fn replace_cmd() {
     }
 }
 
-pub fn attest_cmd(i2c: &mut I2C<I2CPort1>, aes: &mut AES, trng: &mut TRNG) {
-    host_msg!(Ack);
-    let mut pin_buffer = [0; 6];
-    let mut component_buffer = [0; 16];
-    let (pin, component) = {
-        let pin_len = read_arg(&mut pin_buffer);
-        host_msg!(Ack);
-        let component_len = read_arg(&mut component_buffer);
-        (
-            unsafe { from_utf8_unchecked(&pin_buffer[..pin_len]) },
-            u32::from_str_radix(
-                &unsafe { from_utf8_unchecked(&component_buffer) }[2..component_len],
-                16,
-            )
-            .unwrap(),
-        )
+pub fn attest_cmd<B: I2cMaster, C: BlockCipher, R: Rng, K: Clock>(
+    i2c: &mut B,
+    aes: &mut C,
+    trng: &mut R,
+    clock: &mut K,
+    pin: &str,
+    component: u32,
+) {
+    let pin_hash = match get_device() {
+        DeviceKind::ApplicationProcessor { pin_hash, .. } => pin_hash,
+        #[allow(unreachable_patterns)]
+        _ => unreachable!("boot_cmd() is only called by ap"),
     };
+    match check_secret(clock, pin, &pin_hash) {
+        Ok(true) => (),
+        Ok(false) => {
+            host_msg!(Error, "Incorrect Pin");
+            return;
+        }
+        Err(e) => {
+            host_msg!(Error, "Flash {:?}", e);
+            return;
+        }
+    }
 
-    if pin
-        != match get_device() {
-            DeviceKind::ApplicationProcessor { ap_pin, .. } => ap_pin,
-            _ => unreachable!("boot_cmd() is only called by ap"),
+    let mut record = AttestationRecord::new();
+    while let Some(index) = record.next_index() {
+        match transact(i2c, aes, trng, clock, component, &AttestRequest { index })
+            .and_then(|response| record.add_chunk(&response.chunk))
+        {
+            Ok(()) => (),
+            Err(err) => {
+                host_msg!(Error, "Attest {:?}", err);
+                return;
+            }
         }
-    {
-        host_msg!(Error, "Incorrect Pin");
-        return;
+    }
+    let attestation = match record.attestation() {
+        Ok(attestation) => attestation,
+        Err(err) => {
+            host_msg!(Error, "Attest {:?}", err);
+            return;
+        }
+    };
+
+    host_msg!(Info, "C>0x{:08x}", component);
+    host_msg!(Info, "LOC>{}", attestation.location);
+    host_msg!(Info, "DATE>{}", attestation.date);
+    host_msg!(Info, "CUST>{}", attestation.customer);
+    host_msg!(Success, "Attest");
+}
+
+#[cfg(all(test, feature = "std"))]
+mod test {
+    use super::*;
+    use crate::{
+        ectf_params::set_device,
+        host_msg::capture,
+        messages::TransactionKind,
+        mock::{FakeClock, MockBus, MockCipher, MockClock, MockRng, MockSlave},
+        security::{secure_slave_transaction, MAX_TRANSACTION_SIZE},
+    };
+    use std::{sync::Mutex, thread};
+
+    const PIN: &str = "123456";
+    const WRONG_PIN: &str = "654321";
+    const DELAY: u64 = LOCKOUT_DELAY_US as u64;
+    const COMPONENTS: [u32; 2] = [0x11111124, 0x11111125];
+
+    /// The flash and `LOCKOUT_SERVED` are shared by every test.
+    static LOCK: Mutex<()> = Mutex::new(());
+
+    /// Starts the AP as after a power cycle, with `failed_attempts` in flash.
+    /// The flash keeps the `COMPONENTS` of the first power on.
+    fn power_on(failed_attempts: u32) {
+        set_device(DeviceKind::ApplicationProcessor {
+            pin_hash: [0; HASH_SIZE],
+            token_hash: [0; HASH_SIZE],
+            boot_msg: "",
+            comp_ids: &COMPONENTS,
+        });
+        flash::init(flash::MAGIC).unwrap();
+        flash::set_failed_attempts(failed_attempts).unwrap();
+        unsafe { LOCKOUT_SERVED = false };
     }
 
-    // TODO: securly get data from components
-    _ = (i2c, aes, trng);
+    #[test]
+    fn test_wrong_secret_is_delayed() {
+        let _lock = LOCK.lock().unwrap();
+        power_on(0);
+        let pin_hash = secret_hash(PIN.as_bytes());
+        let mut clock = FakeClock(0);
 
-    let attestation_loc = "";
-    let attestation_date = "";
-    let attestation_customer = "";
+        assert!(matches!(check_secret(&mut clock, PIN, &pin_hash), Ok(true)));
+        assert_eq!(clock.0, 0);
 
-    host_msg!(Info, "C>0x{:x}", component);
-    host_msg!(Info, "LOC>{}", attestation_loc);
-    host_msg!(Info, "DATE>{}", attestation_date);
-    host_msg!(Info, "CUST>{}", attestation_customer);
-    host_msg!(Success, "Attest");
+        assert!(matches!(
+            check_secret(&mut clock, WRONG_PIN, &pin_hash),
+            Ok(false)
+        ));
+        assert_eq!(clock.0, DELAY);
+        assert_eq!(flash::get_failed_attempts().unwrap(), 1);
+
+        assert!(matches!(
+            check_secret(&mut clock, WRONG_PIN, &pin_hash),
+            Ok(false)
+        ));
+        assert_eq!(clock.0, 2 * DELAY);
+        assert_eq!(flash::get_failed_attempts().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_right_secret_resets_count() {
+        let _lock = LOCK.lock().unwrap();
+        power_on(0);
+        let pin_hash = secret_hash(PIN.as_bytes());
+        let mut clock = FakeClock(0);
+
+        assert!(matches!(
+            check_secret(&mut clock, WRONG_PIN, &pin_hash),
+            Ok(false)
+        ));
+        assert!(matches!(check_secret(&mut clock, PIN, &pin_hash), Ok(true)));
+        // The delay for the wrong guess was already served in this boot.
+        assert_eq!(clock.0, DELAY);
+        assert_eq!(flash::get_failed_attempts().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_power_cycle_delays_next_attempt() {
+        let _lock = LOCK.lock().unwrap();
+        // The power went out while a wrong guess was being delayed.
+        power_on(1);
+        let pin_hash = secret_hash(PIN.as_bytes());
+        let mut clock = FakeClock(0);
+
+        assert!(matches!(check_secret(&mut clock, PIN, &pin_hash), Ok(true)));
+        assert_eq!(clock.0, DELAY);
+        assert_eq!(flash::get_failed_attempts().unwrap(), 0);
+    }
+
+    fn attach(bus: &MockBus, id: u32) -> MockSlave {
+        bus.attach(I2cAddress::try_from(id).unwrap().into())
+    }
+
+    /// Serves the transactions of component `id` until it has answered
+    /// `requests` of them.
+    fn serve(mut i2c: MockSlave, id: u32, requests: usize) {
+        let mut clock = MockClock::new();
+        let mut served = 0;
+        while served < requests {
+            let result = secure_slave_transaction(
+                &mut i2c,
+                &mut MockCipher::new(),
+                &mut MockRng::new(id),
+                &mut clock,
+                &component_key(id),
+                |kind| match kind {
+                    TransactionKind::List(request) => {
+                        request.respond(ListResponse { id: Some(id) })
+                    }
+                    TransactionKind::Boot(request) => request.respond(BootResponse::new("")),
+                    _ => [0u8; MAX_TRANSACTION_SIZE],
+                },
+            );
+            served += result.is_ok() as usize;
+        }
+    }
+
+    #[test]
+    fn test_boot_reports_components_confirmed_booted() {
+        let _lock = LOCK.lock().unwrap();
+        power_on(0);
+        let [first, second] = COMPONENTS;
+        let bus = MockBus::new();
+        // The second component answers the list round, then drops off the bus.
+        let components = [
+            thread::spawn({
+                let i2c = attach(&bus, first);
+                move || serve(i2c, first, 2)
+            }),
+            thread::spawn({
+                let i2c = attach(&bus, second);
+                move || serve(i2c, second, 1)
+            }),
+        ];
+
+        let (booted, output) = capture(|| {
+            boot_cmd(
+                &mut bus.master(),
+                &mut MockCipher::new(),
+                &mut MockRng::new(1),
+                &mut FakeClock(0),
+            )
+        });
+        drop(bus);
+        for component in components {
+            component.join().unwrap();
+        }
+
+        assert!(!booted);
+        assert!(output.contains("%error: Boot failed on 0x11111125: "));
+        assert!(output.contains(", its state is unknown, 1 of 2 components confirmed booted%"));
+    }
 }
@@ -0,0 +1,144 @@
+use crate::{
+    attestation::Attestation,
+    ectf_params::{get_device, DeviceKind},
+    hal::{BlockCipher, Clock, I2cSlave, Rng},
+    host_msg,
+    kdf::KEY_SIZE,
+    messages::{AttestResponse, BootResponse, ListResponse, Request, TransactionKind},
+    post_boot::{self, get_mut_mailbox, get_mut_peripherals, Mailbox, Message, Peripherals},
+    secret::COMPONENT_KEY,
+    security::secure_slave_transaction,
+};
+use max78000_hal::error::{ErrorKind, Result};
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum ComponentState {
+    /// Answering List, Attest and Boot for the AP.
+    PreBoot,
+    /// The AP booted us, the component application is running and talks to the
+    /// AP through `secure_send`/`secure_receive`.
+    Booted,
+}
+
+fn attestation() -> Attestation<'static> {
+    match get_device() {
+        DeviceKind::Component {
+            attestation_loc,
+            attestation_date,
+            attestation_customer,
+            ..
+        } => Attestation {
+            location: attestation_loc,
+            date: attestation_date,
+            customer: attestation_customer,
+        },
+        #[allow(unreachable_patterns)]
+        _ => unreachable!("attestation() is only called by components"),
+    }
+}
+
+/// Services a single transaction from the AP and returns the state the
+/// component is in afterwards. Only a booted component has a `mailbox` to
+/// serve `Raw` requests from.
+pub fn serve_transaction<B: I2cSlave, C: BlockCipher, R: Rng, K: Clock>(
+    i2c: &mut B,
+    aes: &mut C,
+    trng: &mut R,
+    clock: &mut K,
+    key: &[u8; KEY_SIZE],
+    mailbox: Option<&mut Mailbox>,
+) -> Result<ComponentState> {
+    let mut next_state = match mailbox {
+        Some(_) => ComponentState::Booted,
+        None => ComponentState::PreBoot,
+    };
+
+    let (id, boot_msg) = match get_device() {
+        DeviceKind::Component { id, boot_msg, .. } => (id, boot_msg),
+        #[allow(unreachable_patterns)]
+        _ => unreachable!("serve_transaction() is only called by components"),
+    };
+
+    secure_slave_transaction(i2c, aes, trng, clock, key, |transaction_kind| {
+        use TransactionKind::*;
+        match transaction_kind {
+            List(request) => request.respond(ListResponse { id: Some(id) }),
+            Boot(request) => {
+                next_state = ComponentState::Booted;
+                request.respond(BootResponse::new(boot_msg))
+            }
+            Attest(request) => request.respond(AttestResponse {
+                chunk: attestation().chunk(request.index),
+            }),
+            Raw(request) => request.respond(match mailbox {
+                Some(mailbox) => mailbox.serve(request),
+                None => post_boot::refuse(),
+            }),
+        }
+    })?;
+
+    Ok(next_state)
+}
+
+/// Answers the AP until it boots us.
+pub fn run<B: I2cSlave, C: BlockCipher, R: Rng, K: Clock>(
+    i2c: &mut B,
+    aes: &mut C,
+    trng: &mut R,
+    clock: &mut K,
+    key: &[u8; KEY_SIZE],
+) {
+    let boot_msg = match get_device() {
+        DeviceKind::Component { boot_msg, .. } => boot_msg,
+        #[allow(unreachable_patterns)]
+        _ => unreachable!("component::run() is only called by components"),
+    };
+
+    loop {
+        match serve_transaction(i2c, aes, trng, clock, key, None) {
+            Ok(ComponentState::Booted) => break,
+            Ok(ComponentState::PreBoot) => host_msg!(Debug, "Sec Slave TX OK"),
+            Err(ErrorKind::Abort) => (),
+            Err(ErrorKind::NoneAvailable) => (),
+            Err(err) => host_msg!(Error, "{:?}", err),
+        }
+    }
+
+    host_msg!(Info, "COMP>{}", boot_msg);
+}
+
+/// Keeps the AP served until `done` holds, dropping transactions that fail.
+fn serve_until<Done>(mailbox: &mut Mailbox, done: Done) -> Result<()>
+where
+    Done: Fn(&Mailbox) -> bool,
+{
+    let mut peripherals = get_mut_peripherals().ok_or(ErrorKind::Uninitialized)?;
+    let Peripherals {
+        i2c,
+        aes,
+        trng,
+        clock,
+    } = &mut *peripherals;
+    while !done(mailbox) {
+        match serve_transaction(i2c, aes, trng, clock, &COMPONENT_KEY, Some(&mut *mailbox)) {
+            Ok(_) | Err(ErrorKind::Abort) | Err(ErrorKind::NoneAvailable) => (),
+            Err(err) => host_msg!(Debug, "{:?}", err),
+        }
+    }
+
+    Ok(())
+}
+
+/// Blocks until the AP has picked up `message`.
+pub fn send(message: Message) -> Result<()> {
+    let mailbox = get_mut_mailbox();
+    mailbox.post(message)?;
+    serve_until(mailbox, |mailbox| !mailbox.is_sending())
+}
+
+/// Blocks until the AP sends a message.
+pub fn receive() -> Result<Message> {
+    let mailbox = get_mut_mailbox();
+    serve_until(mailbox, |mailbox| mailbox.is_receiving())?;
+    mailbox.take().ok_or(ErrorKind::NoneAvailable)
+}
@@ -0,0 +1,44 @@
+//! Comparisons of secrets (PINs, tokens, tags) whose timing doesn't depend on
+//! where the inputs first differ.
+
+use core::hint::black_box;
+
+/// Compares `actual` against the secret `expected`. Always walks all of
+/// `expected`, so neither a mismatch nor a shorter `actual` ends it early.
+pub fn eq(expected: &[u8], actual: &[u8]) -> bool {
+    let mut diff = (expected.len() != actual.len()) as u8;
+    for (i, expected) in expected.iter().enumerate() {
+        diff |= expected ^ actual.get(i).copied().unwrap_or(!expected);
+        diff = black_box(diff);
+    }
+    diff == 0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_equal() {
+        assert!(eq(b"123456", b"123456"));
+        assert!(eq(b"", b""));
+    }
+
+    #[test]
+    fn test_unequal() {
+        let expected = *b"0123456789abcdef";
+        for i in 0..expected.len() {
+            let mut actual = expected;
+            actual[i] ^= 0x80;
+            assert!(!eq(&expected, &actual));
+        }
+    }
+
+    #[test]
+    fn test_length_mismatch() {
+        assert!(!eq(b"123456", b"12345"));
+        assert!(!eq(b"123456", b"1234567"));
+        assert!(!eq(b"123456", b""));
+        assert!(!eq(b"", b"123456"));
+    }
+}
@@ -0,0 +1,255 @@
+//! EAX authenticated encryption (AES-CTR + AES-CMAC) built on top of the
+//! hardware `AES` engine, which only gives us raw block encryption.
+
+use crate::{constant_time, hal::BlockCipher};
+use max78000_hal::error::{ErrorKind, Result};
+
+pub const BLOCK_SIZE: usize = 16;
+pub const TAG_SIZE: usize = BLOCK_SIZE;
+
+pub type Block = [u8; BLOCK_SIZE];
+
+const NONCE_TWEAK: u8 = 0;
+const HEADER_TWEAK: u8 = 1;
+const CIPHERTEXT_TWEAK: u8 = 2;
+
+fn xor_block(block: &mut Block, other: &[u8]) {
+    block
+        .iter_mut()
+        .zip(other.iter())
+        .for_each(|(block, other)| *block ^= other);
+}
+
+/// Multiplication by `x` in GF(2^128), used to derive the CMAC subkeys.
+fn double(block: Block) -> Block {
+    let block = u128::from_be_bytes(block);
+    let doubled = block << 1;
+    match block >> 127 {
+        0 => doubled.to_be_bytes(),
+        _ => (doubled ^ 0x87).to_be_bytes(),
+    }
+}
+
+/// CMAC over `[0; 15] || tweak || data`, the tweaked OMAC used by EAX.
+fn omac<C: BlockCipher>(aes: &mut C, tweak: u8, data: &[u8]) -> Block {
+    let k1 = double(aes.encrypt_block([0u8; BLOCK_SIZE]));
+    let k2 = double(k1);
+
+    let mut mac = [0u8; BLOCK_SIZE];
+    mac[BLOCK_SIZE - 1] = tweak;
+    if data.is_empty() {
+        xor_block(&mut mac, &k1);
+        return aes.encrypt_block(mac);
+    }
+    mac = aes.encrypt_block(mac);
+
+    let mut chunks = data.chunks(BLOCK_SIZE).peekable();
+    while let Some(chunk) = chunks.next() {
+        xor_block(&mut mac, chunk);
+        if chunks.peek().is_none() {
+            if chunk.len() == BLOCK_SIZE {
+                xor_block(&mut mac, &k1);
+            } else {
+                mac[chunk.len()] ^= 0x80;
+                xor_block(&mut mac, &k2);
+            }
+        }
+        mac = aes.encrypt_block(mac);
+    }
+
+    mac
+}
+
+fn ctr<C: BlockCipher>(aes: &mut C, counter: Block, data: &mut [u8]) {
+    let mut counter = u128::from_be_bytes(counter);
+    data.chunks_mut(BLOCK_SIZE).for_each(|chunk| {
+        let keystream = aes.encrypt_block(counter.to_be_bytes());
+        chunk
+            .iter_mut()
+            .zip(keystream)
+            .for_each(|(byte, key)| *byte ^= key);
+        counter = counter.wrapping_add(1);
+    });
+}
+
+fn tag<C: BlockCipher>(aes: &mut C, nonce: &Block, header: &[u8], ciphertext: &[u8]) -> Block {
+    let mut tag = *nonce;
+    xor_block(&mut tag, &omac(aes, HEADER_TWEAK, header));
+    xor_block(&mut tag, &omac(aes, CIPHERTEXT_TWEAK, ciphertext));
+    tag
+}
+
+/// Encrypts `data` in place and returns the tag authenticating `header` and
+/// `data`. The caller must have already loaded the key into `aes`.
+pub fn seal<C: BlockCipher>(aes: &mut C, nonce: &[u8], header: &[u8], data: &mut [u8]) -> Block {
+    let nonce = omac(aes, NONCE_TWEAK, nonce);
+    ctr(aes, nonce, data);
+    tag(aes, &nonce, header, data)
+}
+
+/// Verifies `tag` and only then decrypts `data` in place.
+///
+/// Returns `ErrorKind::Invalid` if the frame was tampered with, in which case
+/// `data` is left untouched.
+pub fn open<C: BlockCipher>(
+    aes: &mut C,
+    nonce: &[u8],
+    header: &[u8],
+    data: &mut [u8],
+    expected: &[u8],
+) -> Result<()> {
+    if expected.len() != TAG_SIZE {
+        return Err(ErrorKind::Invalid);
+    }
+
+    let nonce = omac(aes, NONCE_TWEAK, nonce);
+    let actual = tag(aes, &nonce, header, data);
+
+    if !constant_time::eq(&actual, expected) {
+        return Err(ErrorKind::Invalid);
+    }
+
+    ctr(aes, nonce, data);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mock::MockCipher;
+    use std::vec::Vec;
+
+    struct Vector {
+        key: &'static str,
+        nonce: &'static str,
+        header: &'static str,
+        msg: &'static str,
+        /// Ciphertext followed by the tag.
+        cipher: &'static str,
+    }
+
+    /// The test vectors from the EAX paper (Bellare, Rogaway and Wagner).
+    const VECTORS: &[Vector] = &[
+        Vector {
+            key: "233952DEE4D5ED5F9B9C6D6FF80FF478",
+            nonce: "62EC67F9C3A4A407FCB2A8C49031A8B3",
+            header: "6BFB914FD07EAE6B",
+            msg: "",
+            cipher: "E037830E8389F27B025A2D6527E79D01",
+        },
+        Vector {
+            key: "91945D3F4DCBEE0BF45EF52255F095A4",
+            nonce: "BECAF043B0A23D843194BA972C66DEBD",
+            header: "FA3BFD4806EB53FA",
+            msg: "F7FB",
+            cipher: "19DD5C4C9331049D0BDAB0277408F67967E5",
+        },
+        Vector {
+            key: "01F74AD64077F2E704C0F60ADA3DD523",
+            nonce: "70C3DB4F0D26368400A10ED05D2BFF5E",
+            header: "234A3463C1264AC6",
+            msg: "1A47CB4933",
+            cipher: "D851D5BAE03A59F238A23E39199DC9266626C40F80",
+        },
+        Vector {
+            key: "D07CF6CBB7F313BDDE66B727AFD3C5E8",
+            nonce: "8408DFFF3C1A2B1292DC199E46B7D617",
+            header: "33CCE2EABFF5A79D",
+            msg: "481C9E39B1",
+            cipher: "632A9D131AD4C168A4225D8E1FF755939974A7BEDE",
+        },
+        Vector {
+            key: "35B6D0580005BBC12B0587124557D2C2",
+            nonce: "FDB6B06676EEDC5C61D74276E1F8E816",
+            header: "AEB96EAEBE2970E9",
+            msg: "40D0C07DA5E4",
+            cipher: "071DFE16C675CB0677E536F73AFE6A14B74EE49844DD",
+        },
+        Vector {
+            key: "BD8E6E11475E60B268784C38C62FEB22",
+            nonce: "6EAC5C93072D8E8513F750935E46DA1B",
+            header: "D4482D1CA78DCE0F",
+            msg: "4DE3B35C3FC039245BD1FB7D",
+            cipher: "835BB4F15D743E350E728414ABB8644FD6CCB86947C5E10590210A4F",
+        },
+        Vector {
+            key: "7C77D6E813BED5AC98BAA417477A2E7D",
+            nonce: "1A8C98DCD73D38393B2BF1569DEEFC19",
+            header: "65D2017990D62528",
+            msg: "8B0A79306C9CE7ED99DAE4F87F8DD61636",
+            cipher: "02083E3979DA014812F59F11D52630DA30137327D10649B0AA6E1C181DB617D7F2",
+        },
+        Vector {
+            key: "5FFF20CAFAB119CA2FC73549E20F5B0D",
+            nonce: "DDE59B97D722156D4D9AFF2BC7559826",
+            header: "54B9F04E6A09189A",
+            msg: "1BDA122BCE8A8DBAF1877D962B8592DD2D56",
+            cipher: "2EC47B2C4954A489AFC7BA4897EDCDAE8CC33B60450599BD02C96382902AEF7F832A",
+        },
+        Vector {
+            key: "A4A4782BCFFD3EC5E7EF6D8C34A56123",
+            nonce: "B781FCF2F75FA5A8DE97A9CA48E522EC",
+            header: "899A175897561D7E",
+            msg: "6CF36720872B8513F6EAB1A8A44438D5EF11",
+            cipher: "0DE18FD0FDD91E7AF19F1D8EE8733938B1E8E7F6D2231618102FDB7FE55FF1991700",
+        },
+        Vector {
+            key: "8395FCF1E95BEBD697BD010BC766AAC3",
+            nonce: "22E7ADD93CFC6393C57EC0B3C17D6B44",
+            header: "126735FCC320D25A",
+            msg: "CA40D7446E545FFAED3BD12A740A659FFBBB3CEAB7",
+            cipher: "CB8920F87A6C75CFF39627B56E3ED197C552D295A7CFC46AFC253B4652B1AF3795B124AB6E",
+        },
+    ];
+
+    fn hex(digits: &str) -> Vec<u8> {
+        (0..digits.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&digits[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn cipher(key: &str) -> MockCipher {
+        let mut aes = MockCipher::new();
+        aes.set_key(&hex(key).try_into().unwrap());
+        aes
+    }
+
+    #[test]
+    fn test_vectors() {
+        for vector in VECTORS {
+            let mut aes = cipher(vector.key);
+            let (nonce, header) = (hex(vector.nonce), hex(vector.header));
+            let expected = hex(vector.cipher);
+            let (ciphertext, expected_tag) = expected.split_at(expected.len() - TAG_SIZE);
+
+            let mut data = hex(vector.msg);
+            let tag = seal(&mut aes, &nonce, &header, &mut data);
+            assert_eq!(data, ciphertext, "ciphertext for key {}", vector.key);
+            assert_eq!(tag, expected_tag, "tag for key {}", vector.key);
+
+            assert!(open(&mut aes, &nonce, &header, &mut data, &tag).is_ok());
+            assert_eq!(data, hex(vector.msg));
+        }
+    }
+
+    #[test]
+    fn test_flipped_bit_is_rejected() {
+        let vector = &VECTORS[VECTORS.len() - 1];
+        let mut aes = cipher(vector.key);
+        let (nonce, header) = (hex(vector.nonce), hex(vector.header));
+        let sealed = hex(vector.cipher);
+        let (ciphertext, tag) = sealed.split_at(sealed.len() - TAG_SIZE);
+
+        for bit in 0..ciphertext.len() * 8 {
+            let mut data = ciphertext.to_vec();
+            data[bit / 8] ^= 1 << (bit % 8);
+            let tampered = data.clone();
+            assert!(matches!(
+                open(&mut aes, &nonce, &header, &mut data, tag),
+                Err(ErrorKind::Invalid)
+            ));
+            assert_eq!(data, tampered);
+        }
+    }
+}
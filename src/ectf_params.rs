@@ -1,5 +1,9 @@
+#[cfg(not(feature = "std"))]
 use core::ffi::{c_char, c_uint, CStr};
 
+#[cfg(feature = "ap")]
+use crate::kdf::HASH_SIZE;
+
 /*
 // rust_ectf_params.c
 #include <stdint.h>
@@ -16,9 +20,9 @@ struct extern_comp {
     const char* attestation_customer;
 }
 
+// AP_PIN and AP_TOKEN stay out of the image, the AP only carries their hashes
+// from `build.rs`.
 strcut extern_ap {
-    const char* ap_pin;
-    const char* ap_token;
     const char* boot_msg;
     uint32_t* comp_ids;
     uint32_t comp_num;
@@ -71,8 +75,6 @@ extern_ap get_ap(void) {
     uint32_t comp_ids[COMPONENT_CNT] = { COMPONENT_IDS };
 
     extern_ap ap = {
-        .ap_pin = AP_PIN,
-        .ap_token = AP_TOKEN,
         .boot_msg = AP_BOOT_MSG,
         .comp_ids = (uint32_t*)comp_ids,
         .comp_num = COMPONENT_CNT,
@@ -83,15 +85,16 @@ extern_ap get_ap(void) {
 #endif
 */
 
+#[cfg(all(feature = "ap", not(feature = "std")))]
 #[repr(C)]
 struct ExternAP {
-    ap_pin: *const c_char,
-    ap_token: *const c_char,
     boot_msg: *const c_char,
-    comp_ids: *const c_uint,
-    comp_num: c_uint,
+    // Only here to match `extern_ap`, the flash reads them on the C side.
+    _comp_ids: *const c_uint,
+    _comp_num: c_uint,
 }
 
+#[cfg(all(feature = "component", not(feature = "std")))]
 #[repr(C)]
 struct ExternComp {
     id: c_uint,
@@ -101,14 +104,18 @@ struct ExternComp {
     attestation_customer: *const c_char,
 }
 
+#[cfg(not(feature = "std"))]
 extern "C" {
     fn comp_or_ap() -> i32;
+    #[cfg(feature = "component")]
     fn get_comp() -> ExternComp;
+    #[cfg(feature = "ap")]
     fn get_ap() -> ExternAP;
 }
 
 #[derive(Clone)]
 pub enum DeviceKind {
+    #[cfg(feature = "component")]
     Component {
         id: u32,
         boot_msg: &'static str,
@@ -116,17 +123,24 @@ pub enum DeviceKind {
         attestation_date: &'static str,
         attestation_customer: &'static str,
     },
+    #[cfg(feature = "ap")]
     ApplicationProcessor {
-        ap_pin: &'static str,
-        ap_token: &'static str,
+        /// `secret::secret_hash` of the PIN and replacement token.
+        pin_hash: [u8; HASH_SIZE],
+        token_hash: [u8; HASH_SIZE],
         boot_msg: &'static str,
+        /// What the host flash is provisioned with, see `flash::init`. The
+        /// firmware's flash gets them on the C side.
+        #[cfg(feature = "std")]
         comp_ids: &'static [u32],
     },
 }
 
+#[cfg(not(feature = "std"))]
 pub fn get_device() -> DeviceKind {
     match unsafe { comp_or_ap() } {
         // Comp
+        #[cfg(feature = "component")]
         0 => {
             let c_comp = unsafe { get_comp() };
 
@@ -151,23 +165,38 @@ pub fn get_device() -> DeviceKind {
             }
         }
         // Ap
+        #[cfg(feature = "ap")]
         1 => {
             let c_ap = unsafe { get_ap() };
 
-            let ap_pin = unsafe { CStr::from_ptr(c_ap.ap_pin) }.to_str().unwrap();
-            let ap_token = unsafe { CStr::from_ptr(c_ap.ap_token) }.to_str().unwrap();
             let boot_msg = unsafe { CStr::from_ptr(c_ap.boot_msg) }.to_str().unwrap();
-            let comp_ids =
-                unsafe { core::slice::from_raw_parts(c_ap.comp_ids, c_ap.comp_num as usize) };
 
             DeviceKind::ApplicationProcessor {
-                ap_pin,
-                ap_token,
+                pin_hash: crate::secret::AP_PIN_HASH,
+                token_hash: crate::secret::AP_TOKEN_HASH,
                 boot_msg,
-                comp_ids,
             }
         }
 
         _ => unreachable!("We should not have anything other then 0=comp, 1=ap for 'comp_or_ap()'"),
     }
 }
+
+#[cfg(feature = "std")]
+std::thread_local! {
+    static DEVICE: core::cell::RefCell<Option<DeviceKind>> = const { core::cell::RefCell::new(None) };
+}
+
+/// Host builds have no `ectf_params.h`, so every simulated device sets its
+/// parameters on the thread it runs on.
+#[cfg(feature = "std")]
+pub fn set_device(device: DeviceKind) {
+    DEVICE.with(|current| *current.borrow_mut() = Some(device));
+}
+
+#[cfg(feature = "std")]
+pub fn get_device() -> DeviceKind {
+    DEVICE
+        .with(|current| current.borrow().clone())
+        .expect("set_device() was not called on this thread")
+}
@@ -1,19 +1,129 @@
 use max78000_hal::error::{ErrorKind, Result};
 
+/// Marks the flash as initialised by us, see `init_flash`. Changes whenever
+/// `FlashEntry` does, so boards provisioned with an older layout get
+/// provisioned again instead of reading it as the new one.
+pub const MAGIC: u32 = 0x4B1F;
+
 static mut FLASH: Option<FlashEntry> = None;
 
+/*
+// application_processor.c
+typedef struct {
+    uint32_t flash_magic;
+    uint32_t component_cnt;
+    uint32_t component_ids[32];
+} flash_entry;
+
+// The failed attempt count gets a page of its own, since every PIN or token
+// attempt rewrites it, and a power cut halfway through rewriting a page loses
+// all of it. It sat at the end of `flash_entry` while `MAGIC` was 0x4B1E.
+#define ATTEMPTS_ADDR (FLASH_ADDR - MXC_FLASH_PAGE_SIZE)
+
+uint32_t read_failed_attempts(void) {
+    uint32_t count;
+    flash_simple_read(ATTEMPTS_ADDR, &count, sizeof(count));
+    return count;
+}
+
+void write_failed_attempts(uint32_t count) {
+    flash_simple_erase_page(ATTEMPTS_ADDR);
+    flash_simple_write(ATTEMPTS_ADDR, &count, sizeof(count));
+}
+
+int init_flash(uint32_t magic) {
+    flash_simple_init();
+    flash_simple_read(FLASH_ADDR, (uint32_t*)&flash_status, sizeof(flash_entry));
+
+    if (flash_status.flash_magic != magic) {
+        uint32_t component_ids[COMPONENT_CNT] = {COMPONENT_IDS};
+
+        flash_status.flash_magic = magic;
+        flash_status.component_cnt = COMPONENT_CNT;
+        memcpy(flash_status.component_ids, component_ids, COMPONENT_CNT * sizeof(uint32_t));
+
+        write_failed_attempts(0);
+        flash_simple_erase_page(FLASH_ADDR);
+        flash_simple_write(FLASH_ADDR, (uint32_t*)&flash_status, sizeof(flash_entry));
+    }
+    return 0;
+}
+
+flash_entry read_flash(void) {
+    return flash_status;
+}
+
+void write_flash(const flash_entry* entry) {
+    flash_status = *entry;
+    flash_simple_erase_page(FLASH_ADDR);
+    flash_simple_write(FLASH_ADDR, (uint32_t*)&flash_status, sizeof(flash_entry));
+}
+*/
+
+/// Laid out like `flash_entry` on the C side above, which `init_flash`
+/// provisions.
 #[repr(C)]
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 struct FlashEntry {
     flash_magic: u32,
     component_count: u32,
     component_ids: [u32; 32],
 }
 
+#[cfg(not(feature = "std"))]
 extern "C" {
     fn init_flash(magic: u32) -> i32;
     fn read_flash() -> FlashEntry;
     fn write_flash(entry: &FlashEntry);
+    fn read_failed_attempts() -> u32;
+    fn write_failed_attempts(count: u32);
+}
+
+/// Host builds keep the flash in memory, provisioned from `get_device` the
+/// first time it is initialised, just like `init_flash` does on the board.
+#[cfg(feature = "std")]
+static HOST_FLASH: std::sync::Mutex<Option<FlashEntry>> = std::sync::Mutex::new(None);
+#[cfg(feature = "std")]
+static HOST_FAILED_ATTEMPTS: std::sync::atomic::AtomicU32 = std::sync::atomic::AtomicU32::new(0);
+
+#[cfg(feature = "std")]
+unsafe fn init_flash(magic: u32) -> i32 {
+    use crate::ectf_params::{get_device, DeviceKind};
+
+    let comp_ids = match get_device() {
+        DeviceKind::ApplicationProcessor { comp_ids, .. } => comp_ids,
+        #[allow(unreachable_patterns)]
+        _ => unreachable!("flash is only used by the ap"),
+    };
+    let mut component_ids = [0; 32];
+    component_ids[..comp_ids.len()].copy_from_slice(comp_ids);
+
+    HOST_FLASH.lock().unwrap().get_or_insert(FlashEntry {
+        flash_magic: magic,
+        component_count: comp_ids.len() as u32,
+        component_ids,
+    });
+    0
+}
+
+#[cfg(feature = "std")]
+unsafe fn read_flash() -> FlashEntry {
+    HOST_FLASH.lock().unwrap().clone().unwrap()
+}
+
+#[cfg(feature = "std")]
+unsafe fn write_flash(entry: &FlashEntry) {
+    *HOST_FLASH.lock().unwrap() = Some(entry.clone());
+}
+
+#[cfg(feature = "std")]
+unsafe fn read_failed_attempts() -> u32 {
+    HOST_FAILED_ATTEMPTS.load(std::sync::atomic::Ordering::Relaxed)
+}
+
+#[cfg(feature = "std")]
+unsafe fn write_failed_attempts(count: u32) {
+    HOST_FAILED_ATTEMPTS.store(count, std::sync::atomic::Ordering::Relaxed)
 }
 
 pub fn init(magic: u32) -> Result<()> {
@@ -34,17 +144,41 @@ pub fn get_component_ids() -> Result<&'static [u32]> {
     }
 }
 
-pub fn swap_component(id_old: u32, id_new: u32) -> Result<()> {
-    Ok(unsafe {
-        FLASH
-            .as_mut()
-            .map(|flash| {
-                *flash.component_ids.iter_mut().find(|x| **x == id_old)? = id_new;
-                Some(())
-            })
-            .ok_or(ErrorKind::Uninitialized)?
-            .ok_or(ErrorKind::BadParam)?;
+/// Wrong PINs and tokens since the last right one, see
+/// `commands::check_secret`.
+pub fn get_failed_attempts() -> Result<u32> {
+    unsafe {
+        FLASH.as_ref().ok_or(ErrorKind::Uninitialized)?;
+        Ok(read_failed_attempts())
+    }
+}
+
+/// Applies `change` to the flash entry and writes it back if it succeeds.
+fn update(change: impl FnOnce(&mut FlashEntry) -> Result<()>) -> Result<()> {
+    unsafe {
+        change(FLASH.as_mut().ok_or(ErrorKind::Uninitialized)?)?;
         write_flash(FLASH.as_ref().unwrap());
         FLASH = Some(read_flash());
+    }
+    Ok(())
+}
+
+/// Only rewrites the attempts page, never the one holding the component IDs.
+pub fn set_failed_attempts(count: u32) -> Result<()> {
+    unsafe {
+        FLASH.as_ref().ok_or(ErrorKind::Uninitialized)?;
+        write_failed_attempts(count);
+    }
+    Ok(())
+}
+
+pub fn swap_component(id_old: u32, id_new: u32) -> Result<()> {
+    update(|flash| {
+        *flash
+            .component_ids
+            .iter_mut()
+            .find(|x| **x == id_old)
+            .ok_or(ErrorKind::BadParam)? = id_new;
+        Ok(())
     })
 }
@@ -0,0 +1,266 @@
+//! Payloads of up to `MAX_PAYLOAD_SIZE` bytes split across several `Raw`
+//! transactions. Every fragment is `index || flags || len || data`, and only
+//! the last one may be shorter than `FRAGMENT_DATA_SIZE`.
+
+use crate::messages::MAX_RAW_SIZE;
+use max78000_hal::error::{ErrorKind, Result};
+
+/// The largest message the post-boot API can hand us.
+pub const MAX_PAYLOAD_SIZE: usize = u8::MAX as usize;
+
+/// A fragment fills a `Raw` payload except for the byte in front of it that
+/// says what to do with it.
+pub const FRAGMENT_SIZE: usize = MAX_RAW_SIZE - 1;
+const FRAGMENT_HEADER_SIZE: usize = 3;
+pub const FRAGMENT_DATA_SIZE: usize = FRAGMENT_SIZE - FRAGMENT_HEADER_SIZE;
+
+const LAST: u8 = 1 << 0;
+
+#[derive(Clone, Copy)]
+pub struct Fragment {
+    index: u8,
+    last: bool,
+    len: u8,
+    data: [u8; FRAGMENT_DATA_SIZE],
+}
+
+impl Fragment {
+    /// The `index`th fragment of `payload`, or `None` past its last fragment.
+    /// An empty payload still has one, empty, fragment.
+    pub fn of(payload: &[u8], index: u8) -> Option<Self> {
+        let start = index as usize * FRAGMENT_DATA_SIZE;
+        if start > 0 && start >= payload.len() {
+            return None;
+        }
+
+        let chunk = &payload[start..payload.len().min(start + FRAGMENT_DATA_SIZE)];
+        let mut data = [0u8; FRAGMENT_DATA_SIZE];
+        data[..chunk.len()].copy_from_slice(chunk);
+        Some(Self {
+            index,
+            last: start + chunk.len() == payload.len(),
+            len: chunk.len() as u8,
+            data,
+        })
+    }
+
+    #[cfg(feature = "component")]
+    pub fn is_last(&self) -> bool {
+        self.last
+    }
+
+    pub fn encode(&self) -> [u8; FRAGMENT_SIZE] {
+        let mut bytes = [0u8; FRAGMENT_SIZE];
+        bytes[0] = self.index;
+        bytes[1] = if self.last { LAST } else { 0 };
+        bytes[2] = self.len;
+        bytes[FRAGMENT_HEADER_SIZE..].copy_from_slice(&self.data);
+        bytes
+    }
+
+    /// Fails with `ErrorKind::BadParam` on unknown flags and with
+    /// `ErrorKind::Overflow` if the length doesn't fit in a fragment.
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        let (header, data) = bytes
+            .split_first_chunk::<FRAGMENT_HEADER_SIZE>()
+            .ok_or(ErrorKind::Underflow)?;
+        let [index, flags, len] = *header;
+        if flags & !LAST != 0 {
+            return Err(ErrorKind::BadParam);
+        }
+        if len as usize > FRAGMENT_DATA_SIZE {
+            return Err(ErrorKind::Overflow);
+        }
+
+        let mut fragment = Self {
+            index,
+            last: flags & LAST != 0,
+            len,
+            data: [0u8; FRAGMENT_DATA_SIZE],
+        };
+        fragment.data[..len as usize]
+            .copy_from_slice(data.get(..len as usize).ok_or(ErrorKind::Underflow)?);
+        Ok(fragment)
+    }
+}
+
+/// Every fragment of `payload`, in order.
+#[cfg(feature = "ap")]
+pub fn split(payload: &[u8]) -> impl Iterator<Item = Fragment> + '_ {
+    (0..=u8::MAX).map_while(|index| Fragment::of(payload, index))
+}
+
+/// Puts a payload back together from its fragments.
+pub struct Reassembler {
+    data: [u8; MAX_PAYLOAD_SIZE],
+    len: usize,
+    next_index: u8,
+}
+
+impl Reassembler {
+    pub const fn new() -> Self {
+        Self {
+            data: [0u8; MAX_PAYLOAD_SIZE],
+            len: 0,
+            next_index: 0,
+        }
+    }
+
+    /// Adds the next fragment and returns the payload once its last fragment
+    /// is in. A first fragment always starts a new payload, dropping whatever
+    /// was collected of an earlier one the sender gave up on.
+    ///
+    /// A fragment out of order fails with `ErrorKind::BadState`, a short
+    /// fragment before the last with `ErrorKind::Underflow`, and a payload
+    /// growing past `MAX_PAYLOAD_SIZE` with `ErrorKind::Overflow`. Any of these
+    /// throws away what was collected so far, so the sender has to start over
+    /// from the first fragment.
+    pub fn add(&mut self, fragment: &Fragment) -> Result<Option<&[u8]>> {
+        if fragment.index == 0 {
+            self.reset();
+        }
+        let len = fragment.len as usize;
+        let end = self.len + len;
+        let checked = if fragment.index != self.next_index {
+            Err(ErrorKind::BadState)
+        } else if !fragment.last && len != FRAGMENT_DATA_SIZE {
+            Err(ErrorKind::Underflow)
+        } else if end > MAX_PAYLOAD_SIZE {
+            Err(ErrorKind::Overflow)
+        } else {
+            Ok(())
+        };
+        if let Err(err) = checked {
+            self.reset();
+            return Err(err);
+        }
+
+        self.data[self.len..end].copy_from_slice(&fragment.data[..len]);
+        self.len = end;
+        self.next_index = self.next_index.wrapping_add(1);
+        if !fragment.last {
+            return Ok(None);
+        }
+
+        self.reset();
+        Ok(Some(&self.data[..end]))
+    }
+
+    fn reset(&mut self) {
+        self.len = 0;
+        self.next_index = 0;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn round_trip(payload: &[u8]) {
+        let mut reassembler = Reassembler::new();
+        let mut fragments = split(payload).peekable();
+        while let Some(fragment) = fragments.next() {
+            let fragment = Fragment::decode(&fragment.encode()).unwrap();
+            match reassembler.add(&fragment).unwrap() {
+                Some(received) => {
+                    assert!(fragments.peek().is_none());
+                    assert_eq!(received, payload);
+                    return;
+                }
+                None => assert!(fragments.peek().is_some()),
+            }
+        }
+        panic!("payload of {} bytes never completed", payload.len());
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let payload: [u8; MAX_PAYLOAD_SIZE] = core::array::from_fn(|i| i as u8);
+        for len in [
+            0,
+            1,
+            FRAGMENT_DATA_SIZE,
+            FRAGMENT_DATA_SIZE + 1,
+            MAX_PAYLOAD_SIZE,
+        ] {
+            round_trip(&payload[..len]);
+        }
+    }
+
+    #[test]
+    fn test_out_of_order_fragment_is_rejected() {
+        let payload = [0x55; FRAGMENT_DATA_SIZE * 3];
+        let mut reassembler = Reassembler::new();
+        assert!(reassembler.add(&Fragment::of(&payload, 0).unwrap()).is_ok());
+        assert!(matches!(
+            reassembler.add(&Fragment::of(&payload, 2).unwrap()),
+            Err(ErrorKind::BadState)
+        ));
+
+        // The rejected sequence is gone, the next one starts from scratch.
+        assert!(matches!(
+            reassembler.add(&Fragment::of(&payload, 1).unwrap()),
+            Err(ErrorKind::BadState)
+        ));
+        round_trip(&payload);
+    }
+
+    #[test]
+    fn test_first_fragment_restarts_sequence() {
+        let stale = [0x55; FRAGMENT_DATA_SIZE * 3];
+        let payload = [0xaa; FRAGMENT_DATA_SIZE + 1];
+        let mut reassembler = Reassembler::new();
+        assert!(matches!(
+            reassembler.add(&Fragment::of(&stale, 0).unwrap()),
+            Ok(None)
+        ));
+        assert!(matches!(
+            reassembler.add(&Fragment::of(&stale, 1).unwrap()),
+            Ok(None)
+        ));
+
+        assert!(matches!(
+            reassembler.add(&Fragment::of(&payload, 0).unwrap()),
+            Ok(None)
+        ));
+        let received = reassembler.add(&Fragment::of(&payload, 1).unwrap());
+        assert_eq!(received.unwrap(), Some(&payload[..]));
+    }
+
+    #[test]
+    fn test_truncated_sequence_is_rejected() {
+        let payload = [0x55; FRAGMENT_DATA_SIZE * 2];
+        let mut fragment = Fragment::decode(&Fragment::of(&payload, 0).unwrap().encode()).unwrap();
+        fragment.len -= 1;
+
+        assert!(matches!(
+            Reassembler::new().add(&fragment),
+            Err(ErrorKind::Underflow)
+        ));
+    }
+
+    #[test]
+    fn test_oversized_sequence_is_rejected() {
+        let payload = [0x55; FRAGMENT_DATA_SIZE * 5];
+        let mut reassembler = Reassembler::new();
+        let mut fragments = split(&payload);
+        for fragment in fragments.by_ref().take(4) {
+            assert!(matches!(reassembler.add(&fragment), Ok(None)));
+        }
+        assert!(matches!(
+            reassembler.add(&fragments.next().unwrap()),
+            Err(ErrorKind::Overflow)
+        ));
+    }
+
+    #[test]
+    fn test_malformed_fragment_is_rejected() {
+        let mut bytes = Fragment::of(b"hello", 0).unwrap().encode();
+        bytes[2] = FRAGMENT_DATA_SIZE as u8 + 1;
+        assert!(matches!(Fragment::decode(&bytes), Err(ErrorKind::Overflow)));
+
+        let mut bytes = Fragment::of(b"hello", 0).unwrap().encode();
+        bytes[1] |= 0x80;
+        assert!(matches!(Fragment::decode(&bytes), Err(ErrorKind::BadParam)));
+    }
+}
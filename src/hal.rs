@@ -0,0 +1,159 @@
+//! The parts of the MAX78000 the protocol needs, as traits, so the protocol
+//! can also run on the host against the mocks in `mock`.
+
+#[cfg(feature = "component")]
+use crate::host_msg;
+use crate::{eax::Block, kdf::KEY_SIZE};
+#[cfg(feature = "component")]
+use max78000_hal::error::ErrorKind;
+use max78000_hal::{
+    aes::{AESIterExt, CipherType, Key, AES},
+    error::Result,
+    i2c::{I2CPort1, I2C},
+    trng::TRNG,
+};
+
+#[cfg(feature = "ap")]
+pub trait I2cMaster {
+    /// Writes `tx` to, or reads `rx` from, the slave at `address`.
+    fn master_transaction(
+        &mut self,
+        address: usize,
+        rx: Option<&mut [u8]>,
+        tx: Option<&[u8]>,
+    ) -> Result<()>;
+}
+
+#[cfg(feature = "component")]
+pub trait I2cSlave {
+    /// Copies the master's next write into `rx` and returns its length.
+    ///
+    /// Fails with `ErrorKind::NoneAvailable` while the master isn't writing,
+    /// and with `ErrorKind::Overflow` if the write doesn't fit in `rx`.
+    fn slave_receive(&mut self, rx: &mut [u8]) -> Result<usize>;
+
+    /// Answers the master's next read from `tx`.
+    ///
+    /// Fails with `ErrorKind::NoneAvailable` while the master isn't reading.
+    fn slave_send(&mut self, tx: &mut dyn Iterator<Item = u8>) -> Result<()>;
+}
+
+/// Raw AES block encryption, everything else is built on top of it in `eax`.
+pub trait BlockCipher {
+    fn set_key(&mut self, key: &[u8; KEY_SIZE]);
+    fn encrypt_block(&mut self, block: Block) -> Block;
+}
+
+pub trait Rng {
+    fn next_u32(&mut self) -> u32;
+}
+
+/// A free-running clock, for timeouts and retry deadlines.
+pub trait Clock {
+    /// Microseconds since some fixed point in the past.
+    fn now_us(&mut self) -> u64;
+
+    #[cfg(feature = "ap")]
+    fn delay_us(&mut self, us: u32) {
+        let start = self.now_us();
+        while self.now_us() - start < us as u64 {}
+    }
+}
+
+#[cfg(feature = "ap")]
+impl I2cMaster for I2C<I2CPort1> {
+    fn master_transaction(
+        &mut self,
+        address: usize,
+        rx: Option<&mut [u8]>,
+        tx: Option<&[u8]>,
+    ) -> Result<()> {
+        I2C::master_transaction(self, address, rx, tx)
+    }
+}
+
+#[cfg(feature = "component")]
+impl I2cSlave for I2C<I2CPort1> {
+    fn slave_receive(&mut self, rx: &mut [u8]) -> Result<usize> {
+        match self.slave_manual_pulling(&mut [].into_iter()) {
+            Ok(rx_iter) => {
+                let mut rx_index = 0;
+                for byte in rx_iter {
+                    *rx.get_mut(rx_index).ok_or(ErrorKind::Overflow)? = byte;
+                    rx_index += 1;
+                }
+                Ok(rx_index)
+            }
+            // The master read from us while we had nothing to say.
+            Err(ErrorKind::Underflow) => {
+                if self.transaction_buffer.0 != 0 {
+                    host_msg!(Debug, "Underflow: {}", self.transaction_buffer.0);
+                }
+                Ok(0)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    fn slave_send(&mut self, mut tx: &mut dyn Iterator<Item = u8>) -> Result<()> {
+        self.slave_manual_pulling(&mut tx).map(|_| ())
+    }
+}
+
+impl BlockCipher for AES {
+    fn set_key(&mut self, key: &[u8; KEY_SIZE]) {
+        AES::set_key(self, &Key::Bits128(key));
+    }
+
+    fn encrypt_block(&mut self, block: Block) -> Block {
+        let mut out = Block::default();
+        out.iter_mut()
+            .zip(block.into_iter().cipher(self, CipherType::Encrypt))
+            .for_each(|(out, cipher)| *out = cipher);
+        out
+    }
+}
+
+impl Rng for TRNG {
+    fn next_u32(&mut self) -> u32 {
+        self.get_trng_data()
+    }
+}
+
+/// The Cortex-M4 cycle counter in the DWT, extended to 64 bits. It has to be
+/// read at least once per wrap of the 32-bit counter, about every 40 seconds.
+pub struct CycleCounter {
+    last: u32,
+    cycles: u64,
+}
+
+impl CycleCounter {
+    const DEMCR: *mut u32 = 0xE000_EDFC as *mut u32;
+    const DWT_CTRL: *mut u32 = 0xE000_1000 as *mut u32;
+    const DWT_CYCCNT: *const u32 = 0xE000_1004 as *const u32;
+    const TRCENA: u32 = 1 << 24;
+    const CYCCNTENA: u32 = 1 << 0;
+
+    /// The core runs from the 100 MHz internal oscillator.
+    const CYCLES_PER_US: u64 = 100;
+
+    pub fn init() -> Self {
+        unsafe {
+            Self::DEMCR.write_volatile(Self::DEMCR.read_volatile() | Self::TRCENA);
+            Self::DWT_CTRL.write_volatile(Self::DWT_CTRL.read_volatile() | Self::CYCCNTENA);
+            Self {
+                last: Self::DWT_CYCCNT.read_volatile(),
+                cycles: 0,
+            }
+        }
+    }
+}
+
+impl Clock for CycleCounter {
+    fn now_us(&mut self) -> u64 {
+        let now = unsafe { Self::DWT_CYCCNT.read_volatile() };
+        self.cycles += now.wrapping_sub(self.last) as u64;
+        self.last = now;
+        self.cycles / Self::CYCLES_PER_US
+    }
+}
@@ -0,0 +1,66 @@
+//! `ap_function` and `comp_function` for host builds, running on the mocks
+//! instead of the MAX78000. Used by the simulator in `sim/`.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{
+    addressing::I2cAddress,
+    commands, component,
+    ectf_params::set_device,
+    flash, host_msg,
+    mock::{MockBus, MockCipher, MockClock, MockRng, MockSlave},
+};
+use max78000_hal::error::{ErrorKind, Result};
+
+pub use crate::{
+    args::component_id,
+    ectf_params::DeviceKind,
+    kdf::KEY_SIZE,
+    secret::{component_key, secret_hash},
+};
+
+fn seed() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.subsec_nanos())
+}
+
+/// Attaches the component described by `device` to `bus` at the address its
+/// id maps to.
+pub fn attach(bus: &MockBus, device: &DeviceKind) -> Result<MockSlave> {
+    match device {
+        DeviceKind::Component { id, .. } => Ok(bus.attach(I2cAddress::try_from(*id)?.into())),
+        DeviceKind::ApplicationProcessor { .. } => Err(ErrorKind::BadParam),
+    }
+}
+
+/// Serves host commands from stdin until `boot` succeeds, and returns the ids
+/// of the components it booted.
+pub fn ap_main(bus: &MockBus, device: DeviceKind) -> Result<&'static [u32]> {
+    set_device(device);
+    flash::init(flash::MAGIC)?;
+
+    host_msg!(Debug, "Application Processor Started");
+    commands::run(
+        &mut bus.master(),
+        &mut MockCipher::new(),
+        &mut MockRng::new(seed()),
+        &mut MockClock::new(),
+    );
+    flash::get_component_ids()
+}
+
+/// Answers the AP over `i2c` until it boots us. `key` is normally
+/// `component_key` of the component's id.
+pub fn component_main(mut i2c: MockSlave, device: DeviceKind, key: &[u8; KEY_SIZE]) {
+    set_device(device);
+    host_msg::redirect_to_stderr();
+
+    component::run(
+        &mut i2c,
+        &mut MockCipher::new(),
+        &mut MockRng::new(seed()),
+        &mut MockClock::new(),
+        key,
+    );
+}
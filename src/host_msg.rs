@@ -1,3 +1,4 @@
+#[cfg(all(feature = "ap", not(feature = "std")))]
 use core::ops::{Deref, DerefMut};
 
 use max78000_hal::{
@@ -12,14 +13,17 @@ struct HostMsg {
     board_name: &'static str,
 }
 
+#[cfg(all(feature = "ap", not(feature = "std")))]
 pub struct UartRef<'a>(&'a mut UART<UART0>);
 
+#[cfg(all(feature = "ap", not(feature = "std")))]
 impl<'a> Drop for UartRef<'a> {
     fn drop(&mut self) {
         unsafe { UART_REF = false };
     }
 }
 
+#[cfg(all(feature = "ap", not(feature = "std")))]
 impl<'a> Deref for UartRef<'a> {
     type Target = UART<UART0>;
     fn deref(&self) -> &Self::Target {
@@ -27,6 +31,7 @@ impl<'a> Deref for UartRef<'a> {
     }
 }
 
+#[cfg(all(feature = "ap", not(feature = "std")))]
 impl<'a> DerefMut for UartRef<'a> {
     fn deref_mut(&mut self) -> &mut Self::Target {
         self.0
@@ -54,30 +59,30 @@ impl core::fmt::Write for HostMsg {
 #[macro_export]
 macro_rules! host_msg {
     (Error, $($arg:tt)*) => {{
-        max78000_hal::debug::_print(format_args!("%error: "));
-        max78000_hal::debug::_print(format_args!($($arg)*));
-        max78000_hal::debug::_print(format_args!("%\n"));
+        $crate::host_msg::_print(format_args!("%error: "));
+        $crate::host_msg::_print(format_args!($($arg)*));
+        $crate::host_msg::_print(format_args!("%\n"));
     }};
     (Success, $($arg:tt)*) => {{
-        max78000_hal::debug::_print(format_args!("%success: "));
-        max78000_hal::debug::_print(format_args!($($arg)*));
-        max78000_hal::debug::_print(format_args!("%\n"));
+        $crate::host_msg::_print(format_args!("%success: "));
+        $crate::host_msg::_print(format_args!($($arg)*));
+        $crate::host_msg::_print(format_args!("%\n"));
     }};
     (Info, $($arg:tt)*) => {{
-        max78000_hal::debug::_print(format_args!("%info: "));
-        max78000_hal::debug::_print(format_args!($($arg)*));
-        max78000_hal::debug::_print(format_args!("%\n"));
+        $crate::host_msg::_print(format_args!("%info: "));
+        $crate::host_msg::_print(format_args!($($arg)*));
+        $crate::host_msg::_print(format_args!("%\n"));
     }};
     (Debug, $($arg:tt)*) => {{
-        max78000_hal::debug::_print(format_args!("%debug: "));
-        max78000_hal::debug::_print(format_args!($($arg)*));
-        max78000_hal::debug::_print(format_args!("%\n"));
+        $crate::host_msg::_print(format_args!("%debug: "));
+        $crate::host_msg::_print(format_args!($($arg)*));
+        $crate::host_msg::_print(format_args!("%\n"));
     }};
     (Prompt, $($arg:tt)*) => {{
-        max78000_hal::debug::_print(format_args!($($arg)*));
+        $crate::host_msg::_print(format_args!($($arg)*));
     }};
     (Ack) => {{
-        max78000_hal::debug::_print(format_args!("%ack%\n"));
+        $crate::host_msg::_print(format_args!("%ack%\n"));
     }};
 }
 
@@ -110,8 +115,10 @@ pub fn setup_uart(board_name: &'static str) {
     host_msg!(Info, "{} Started", board_name);
 }
 
+#[cfg(all(feature = "ap", not(feature = "std")))]
 static mut UART_REF: bool = false;
 
+#[cfg(all(feature = "ap", not(feature = "std")))]
 pub fn get_mut_uart() -> Option<UartRef<'static>> {
     if unsafe { UART_REF } {
         None
@@ -123,6 +130,7 @@ pub fn get_mut_uart() -> Option<UartRef<'static>> {
     }
 }
 
+#[cfg(all(feature = "ap", not(feature = "std")))]
 impl Iterator for UartRef<'static> {
     type Item = u8;
 
@@ -136,12 +144,97 @@ impl Iterator for UartRef<'static> {
     }
 }
 
+#[cfg(not(feature = "std"))]
+pub use max78000_hal::debug::_print;
+
+/// Reads one argument, up to the next `\r`, into `buffer` and returns its
+/// length. An argument longer than `buffer` is still read to its end, but only
+/// what fits is kept.
+#[cfg(all(feature = "ap", not(feature = "std")))]
 pub fn read_arg(buffer: &mut [u8]) -> usize {
     get_mut_uart()
         .unwrap()
-        .take(buffer.len())
         .take_while(|&b| b != b'\r')
         .enumerate()
-        .map(|(i, b)| buffer[i] = b)
+        .map(|(i, b)| {
+            if let Some(slot) = buffer.get_mut(i) {
+                *slot = b;
+            }
+        })
         .count()
 }
+
+#[cfg(feature = "std")]
+std::thread_local! {
+    static TO_STDERR: core::cell::Cell<bool> = const { core::cell::Cell::new(false) };
+}
+
+/// Sends this thread's messages to stderr, leaving stdout to the one device
+/// the host tools talk to.
+#[cfg(feature = "std")]
+pub fn redirect_to_stderr() {
+    TO_STDERR.set(true);
+}
+
+#[cfg(all(test, feature = "std"))]
+std::thread_local! {
+    static CAPTURED: core::cell::RefCell<Option<std::string::String>> =
+        const { core::cell::RefCell::new(None) };
+}
+
+/// Runs `f` and returns what it printed on this thread next to its result,
+/// instead of printing it.
+#[cfg(all(test, feature = "std"))]
+pub fn capture<T>(f: impl FnOnce() -> T) -> (T, std::string::String) {
+    CAPTURED.set(Some(std::string::String::new()));
+    let result = f();
+    (result, CAPTURED.take().unwrap_or_default())
+}
+
+#[cfg(feature = "std")]
+pub fn _print(args: core::fmt::Arguments) {
+    use std::io::Write;
+
+    #[cfg(test)]
+    if CAPTURED.with_borrow_mut(|captured| {
+        captured
+            .as_mut()
+            .map(|captured| _ = core::fmt::Write::write_fmt(captured, args))
+            .is_some()
+    }) {
+        return;
+    }
+
+    if TO_STDERR.get() {
+        _ = std::io::stderr().write_fmt(args);
+    } else {
+        let mut stdout = std::io::stdout();
+        _ = stdout.write_fmt(args);
+        _ = stdout.flush();
+    }
+}
+
+/// Reads one argument from stdin like `read_arg` does from the UART. Unlike
+/// the UART, stdin can close, which ends the simulation.
+#[cfg(feature = "std")]
+pub fn read_arg(buffer: &mut [u8]) -> usize {
+    use std::io::Read;
+
+    let mut len = 0;
+    let mut bytes = std::io::stdin().lock().bytes();
+    loop {
+        match bytes.next() {
+            // Lines may end in "\r\n", so skip what the last argument left behind.
+            Some(Ok(b'\r' | b'\n')) if len == 0 => (),
+            Some(Ok(b'\r' | b'\n')) => break,
+            Some(Ok(byte)) => {
+                if let Some(slot) = buffer.get_mut(len) {
+                    *slot = byte;
+                }
+                len += 1;
+            }
+            Some(Err(_)) | None => std::process::exit(0),
+        }
+    }
+    len
+}
@@ -0,0 +1,145 @@
+//! Derives a distinct key for every component from the deployment secret, so
+//! extracting the key from one component doesn't compromise any other. Also
+//! hashes the AP PIN and token, so the AP image never carries them in the clear.
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+pub const KEY_SIZE: usize = 16;
+pub const SALT_SIZE: usize = 16;
+pub const HASH_SIZE: usize = 32;
+
+const COMPONENT_KEY_LABEL: &[u8] = b"ectf component key";
+const SECRET_SALT_LABEL: &[u8] = b"ectf secret salt";
+
+/// As many as the AP's time limits allow with room to spare. Attest has to
+/// finish within 3 seconds, and besides the hash it may spend up to
+/// `RetryPolicy::DEFAULT`'s deadline of 0.5 seconds on the bus.
+///
+/// Counted rather than timed, on a release build for the Cortex-M4: the
+/// unrolled SHA-256 compression is 2,169 instructions, which `llvm-mca
+/// -mcpu=cortex-m4` puts at 2,218 cycles, about 2,500 with loads taking two.
+/// A round is two compressions plus about 1,000 cycles of copying HMAC state,
+/// so 6,000 cycles, or 60 us at 100 MHz. These rounds take 0.96 seconds, under
+/// half of the 2.5 seconds left, in case a board is slower than the count.
+const SECRET_HASH_ROUNDS: u32 = 16_000;
+
+/// `HMAC-SHA256(deployment_secret, label || component_id)`, truncated to an
+/// AES-128 key.
+pub fn component_key(deployment_secret: &[u8; KEY_SIZE], component_id: u32) -> [u8; KEY_SIZE] {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(deployment_secret).expect("HMAC accepts keys of any length");
+    mac.update(COMPONENT_KEY_LABEL);
+    mac.update(&component_id.to_be_bytes());
+
+    let mut key = [0u8; KEY_SIZE];
+    key.copy_from_slice(&mac.finalize().into_bytes()[..KEY_SIZE]);
+    key
+}
+
+/// `HMAC-SHA256(deployment_secret, label)`, truncated. Every deployment hashes
+/// its PIN and token under its own salt.
+pub fn secret_salt(deployment_secret: &[u8; KEY_SIZE]) -> [u8; SALT_SIZE] {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(deployment_secret).expect("HMAC accepts keys of any length");
+    mac.update(SECRET_SALT_LABEL);
+
+    let mut salt = [0u8; SALT_SIZE];
+    salt.copy_from_slice(&mac.finalize().into_bytes()[..SALT_SIZE]);
+    salt
+}
+
+/// PBKDF2-HMAC-SHA256 of the PIN or token `secret` under `salt`.
+///
+/// This keeps the PIN and token out of the image in the clear, and the salt
+/// keeps precomputed tables from working across deployments. It does not stop
+/// anyone who has dumped the AP image from brute-forcing the PIN. The salt
+/// comes from `DEPLOYMENT_SECRET`, which is in the same image, and a 6 digit
+/// hex PIN has only 2^24 candidates. The rounds stretch that to most of a day on
+/// one CPU core, but only minutes on a GPU. The 16 digit token is out of reach
+/// either way. On the device itself, guessing is throttled by
+/// `check_secret`'s lockout, not by this hash.
+pub fn secret_hash(salt: &[u8; SALT_SIZE], secret: &[u8]) -> [u8; HASH_SIZE] {
+    pbkdf2(secret, salt, SECRET_HASH_ROUNDS)
+}
+
+/// The first, and only, block of PBKDF2-HMAC-SHA256.
+fn pbkdf2(password: &[u8], salt: &[u8], rounds: u32) -> [u8; HASH_SIZE] {
+    let prf = Hmac::<Sha256>::new_from_slice(password).expect("HMAC accepts keys of any length");
+
+    let mut block = prf
+        .clone()
+        .chain_update(salt)
+        .chain_update(1u32.to_be_bytes())
+        .finalize()
+        .into_bytes();
+    let mut hash = [0u8; HASH_SIZE];
+    hash.copy_from_slice(&block);
+    for _ in 1..rounds {
+        block = prf.clone().chain_update(block).finalize().into_bytes();
+        hash.iter_mut()
+            .zip(block)
+            .for_each(|(hash, byte)| *hash ^= byte);
+    }
+    hash
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SECRET: [u8; KEY_SIZE] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15];
+
+    #[test]
+    fn test_component_key() {
+        assert_eq!(
+            component_key(&SECRET, 0x11111125),
+            [
+                0xd4, 0xe2, 0xe4, 0xe8, 0xff, 0xa2, 0xa5, 0xc4, 0x5f, 0xe4, 0xe1, 0x82, 0x6e, 0xb9,
+                0x56, 0x91
+            ]
+        );
+    }
+
+    #[test]
+    fn test_pbkdf2() {
+        assert_eq!(
+            pbkdf2(b"passwd", b"salt", 1),
+            [
+                0x55, 0xac, 0x04, 0x6e, 0x56, 0xe3, 0x08, 0x9f, 0xec, 0x16, 0x91, 0xc2, 0x25, 0x44,
+                0xb6, 0x05, 0xf9, 0x41, 0x85, 0x21, 0x6d, 0xde, 0x04, 0x65, 0xe6, 0x8b, 0x9d, 0x57,
+                0xc2, 0x0d, 0xac, 0xbc
+            ]
+        );
+        assert_eq!(
+            pbkdf2(b"password", b"salt", 4096),
+            [
+                0xc5, 0xe4, 0x78, 0xd5, 0x92, 0x88, 0xc8, 0x41, 0xaa, 0x53, 0x0d, 0xb6, 0x84, 0x5c,
+                0x4c, 0x8d, 0x96, 0x28, 0x93, 0xa0, 0x01, 0xce, 0x4e, 0x11, 0xa4, 0x96, 0x38, 0x73,
+                0xaa, 0x98, 0x13, 0x4a
+            ]
+        );
+    }
+
+    #[test]
+    fn test_secret_hashes_are_salted() {
+        let salt = secret_salt(&SECRET);
+        assert_ne!(secret_hash(&salt, b"123456"), secret_hash(&salt, b"123457"));
+        assert_ne!(
+            secret_hash(&salt, b"123456"),
+            secret_hash(&secret_salt(&[0xff; KEY_SIZE]), b"123456")
+        );
+    }
+
+    #[test]
+    fn test_component_keys_are_distinct() {
+        assert_ne!(
+            component_key(&SECRET, 0x11111124),
+            component_key(&SECRET, 0x11111125)
+        );
+        assert_ne!(
+            component_key(&SECRET, 0x11111124),
+            component_key(&[0xff; KEY_SIZE], 0x11111124)
+        );
+    }
+}
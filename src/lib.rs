@@ -1,95 +1,139 @@
 #![no_std]
-#![feature(iter_array_chunks)]
 
+#[cfg(any(test, feature = "std"))]
+extern crate std;
+
+mod addressing;
+#[cfg(feature = "ap")]
+mod args;
+mod attestation;
+mod checksum;
+#[cfg(feature = "ap")]
 mod commands;
+#[cfg(feature = "component")]
+mod component;
+mod constant_time;
+mod eax;
 mod ectf_params;
+#[cfg(feature = "ap")]
 mod flash;
+mod fragment;
+mod hal;
+#[cfg(all(feature = "std", feature = "ap", feature = "component"))]
+pub mod host;
 mod host_msg;
+// build.rs derives the component's key with the rest of `kdf`.
+#[cfg_attr(not(feature = "ap"), allow(dead_code))]
+mod kdf;
+mod messages;
+#[cfg(any(test, feature = "std"))]
+pub mod mock;
+mod post_boot;
 mod secret;
 mod security;
 
+use crate::{hal::CycleCounter, host_msg::setup_uart};
+use max78000_hal::{aes::AES, i2c::I2C, trng::TRNG};
+
+#[cfg(not(any(test, feature = "std")))]
+use core::{arch::asm, panic::PanicInfo};
+#[cfg(not(any(test, feature = "std")))]
+use max78000_hal::gpio::hardware::led_red;
+
+#[cfg(any(feature = "ap", feature = "component"))]
+use crate::post_boot::Peripherals;
+
+#[cfg(feature = "ap")]
+use core::ptr::copy_nonoverlapping;
+#[cfg(feature = "ap")]
+use max78000_hal::gpio::hardware::led_green;
+
+#[cfg(feature = "component")]
 use crate::{
-    commands::{attest_cmd, boot_cmd, list_cmd, replace_cmd},
-    host_msg::{read_arg, setup_uart},
-    security::MAX_TRANSACTION_SIZE,
-};
-use core::{arch::asm, panic::PanicInfo, ptr::copy_nonoverlapping};
-use max78000_hal::{
-    aes::AES,
-    error::ErrorKind,
-    gpio::hardware::{led_blue, led_green, led_red},
-    i2c::I2C,
-    trng::TRNG,
+    addressing::I2cAddress,
+    ectf_params::{get_device, DeviceKind},
+    secret::{COMPONENT_ID, COMPONENT_KEY},
 };
-use security::{secure_slave_transaction, TransactionKind};
+#[cfg(feature = "component")]
+use max78000_hal::gpio::hardware::led_blue;
 
+#[cfg(feature = "ap")]
 #[no_mangle]
 pub extern "C" fn ap_function() {
-    flash::init(0x4B1D).unwrap();
+    flash::init(flash::MAGIC).unwrap();
     setup_uart("A");
 
     let mut i2c = I2C::init_port_1_master().unwrap();
     let mut aes = AES::init();
     let mut trng = TRNG::init();
+    let mut clock = CycleCounter::init();
 
     _ = led_green().unwrap().set_output(false);
 
     host_msg!(Debug, "Application Processor Started");
 
-    loop {
-        host_msg!(Debug, "Enter Command: ");
-        let mut cmd_rx_buffer = [0; 7];
-        let cmd_bytes_read = read_arg(&mut cmd_rx_buffer);
-        if &cmd_rx_buffer[0..4] == "list".as_bytes() {
-            list_cmd(&mut i2c, &mut aes, &mut trng);
-            continue;
-        }
-
-        if &cmd_rx_buffer[0..4] == b"boot" {
-            boot_cmd(i2c, aes, trng);
-        }
+    commands::run(&mut i2c, &mut aes, &mut trng, &mut clock);
+    post_boot::store_peripherals(Peripherals {
+        i2c,
+        aes,
+        trng,
+        clock,
+    });
 
-        if &cmd_rx_buffer[0..7] == b"replace" {
-            replace_cmd();
-        } else if &cmd_rx_buffer[0..6] == b"attest" {
-            attest_cmd(&mut i2c, &mut aes, &mut trng);
-        } else {
-            host_msg!(Error, "Unrecognized command '{}'", unsafe {
-                core::str::from_utf8_unchecked(&cmd_rx_buffer[..cmd_bytes_read])
-            });
-        }
-    }
+    unsafe { boot() }
 }
 
+#[cfg(feature = "component")]
 #[no_mangle]
 pub extern "C" fn comp_function() {
     setup_uart("C");
 
-    let mut i2c = I2C::init_port_1_slave(0x23).unwrap();
+    let id = match get_device() {
+        DeviceKind::Component { id, .. } => id,
+        #[allow(unreachable_patterns)]
+        _ => unreachable!("comp_function() is only called by components"),
+    };
+    // The key was derived at build time from `ECTF_COMPONENT_ID`, the address
+    // comes from `ectf_params.h`. An image built with one id but provisioned
+    // with another would fail every handshake.
+    assert_eq!(
+        id, COMPONENT_ID,
+        "ectf_params.h has component id 0x{:08x}, but the key was built for 0x{:08x}",
+        id, COMPONENT_ID
+    );
+
+    let mut i2c = I2C::init_port_1_slave(I2cAddress::try_from(id).unwrap().into()).unwrap();
     let mut aes = AES::init();
+    let mut trng = TRNG::init();
+    let mut clock = CycleCounter::init();
 
     _ = led_blue().unwrap().set_output(false);
 
-    loop {
-        match secure_slave_transaction(&mut i2c, &mut aes, |transaction_kind| {
-            use TransactionKind::*;
-            match transaction_kind {
-                List => [0u8; MAX_TRANSACTION_SIZE],
-                Boot => [1u8; MAX_TRANSACTION_SIZE],
-                Attest => [1u8; MAX_TRANSACTION_SIZE],
-                Raw(_) => panic!("Unexpected Raw Data during pre-boot in comp_function()"),
-            }
-        }) {
-            Ok(()) => host_msg!(Debug, "Sec Slave TX OK"),
-            Err(ErrorKind::Abort) => (),
-            Err(ErrorKind::NoneAvailable) => (),
-            Err(err) => host_msg!(Error, "{:?}", err),
-        }
-    }
+    component::run(&mut i2c, &mut aes, &mut trng, &mut clock, &COMPONENT_KEY);
+    post_boot::store_peripherals(Peripherals {
+        i2c,
+        aes,
+        trng,
+        clock,
+    });
+
+    unsafe { boot() }
+}
+
+#[cfg(not(feature = "std"))]
+extern "C" {
+    fn boot() -> !;
+}
+
+/// Host builds have no application to hand over to, see `host`.
+#[cfg(feature = "std")]
+unsafe fn boot() -> ! {
+    unreachable!("host builds never boot the application")
 }
 
 /// Returns the currently provisioned IDs and the number of provisioned IDs for
 /// the current AP. This function is  in uninitialized functionality.
+#[cfg(feature = "ap")]
 pub extern "C" fn get_provisioned_ids(buffer: *mut u32) -> i32 {
     let ids = flash::get_component_ids().unwrap();
     unsafe { copy_nonoverlapping(ids.as_ptr(), buffer, ids.len()) };
@@ -97,17 +141,31 @@ pub extern "C" fn get_provisioned_ids(buffer: *mut u32) -> i32 {
 }
 
 /// Securely send data over `I2C`. This function is utilized in `POST_BOOT` functionality.
+///
+/// Returns 0 on success, or -1 on failure.
+#[no_mangle]
 pub extern "C" fn secure_send(i2c_address: u8, buffer: *const u8, len: u8) -> i32 {
-    _ = (i2c_address, buffer, len);
-    0
+    let bytes = unsafe { core::slice::from_raw_parts(buffer, len as usize) };
+    match post_boot::secure_send(i2c_address, bytes) {
+        Ok(()) => 0,
+        Err(_) => -1,
+    }
 }
 
 /// Securely receive data over `I2C`. This function is utilized in `POST_BOOT` functionality.
+///
+/// Returns the number of bytes written to `buffer`, which must hold at least
+/// 255 bytes, or -1 on failure.
+#[no_mangle]
 pub extern "C" fn secure_receive(i2c_address: u8, buffer: *mut u8) -> i32 {
-    _ = (i2c_address, buffer);
-    0
+    let buffer = unsafe { core::slice::from_raw_parts_mut(buffer, u8::MAX as usize) };
+    match post_boot::secure_receive(i2c_address, buffer) {
+        Ok(len) => len as i32,
+        Err(_) => -1,
+    }
 }
 
+#[cfg(not(any(test, feature = "std")))]
 fn delay() {
     unsafe {
         for _ in 0..1000000 {
@@ -116,7 +174,7 @@ fn delay() {
     }
 }
 
-#[cfg(not(test))]
+#[cfg(not(any(test, feature = "std")))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     let red = led_red().unwrap();
@@ -0,0 +1,418 @@
+//! What the AP can ask of a component, and what it gets back. Every request is
+//! its own type with its own opcode and response type, and `TransactionKind`
+//! is the one place a component looks opcodes up. A new request needs its
+//! types, a `Request` impl and a `TransactionKind` variant, after which the
+//! compiler points at every component that still has to answer it.
+
+use crate::security::MAX_TRANSACTION_SIZE;
+use max78000_hal::error::{ErrorKind, Result};
+
+/// How a request or response travels inside a transaction. Whatever `encode`
+/// leaves out of the `MAX_TRANSACTION_SIZE` bytes is ignored by `decode`.
+pub trait Codec: Sized {
+    fn encode(&self) -> [u8; MAX_TRANSACTION_SIZE];
+    fn decode(bytes: &[u8]) -> Result<Self>;
+}
+
+pub trait Request: Codec {
+    /// Unique among all requests, see `TransactionKind::decode`.
+    const OPCODE: u8;
+    /// The component answers it again the same way, so it may be sent again
+    /// after the response went missing, see `retried_master_transaction`.
+    #[cfg(feature = "ap")]
+    const REPEATABLE: bool;
+    type Response: Codec;
+
+    /// Encodes the answer to this request, so a component can't answer with
+    /// the response of another one.
+    #[cfg(feature = "component")]
+    fn respond(&self, response: Self::Response) -> [u8; MAX_TRANSACTION_SIZE] {
+        response.encode()
+    }
+
+    /// Decodes the answer of a component speaking protocol `version`.
+    #[cfg(feature = "ap")]
+    fn decode_response(_version: u8, bytes: &[u8]) -> Result<Self::Response> {
+        Self::Response::decode(bytes)
+    }
+}
+
+/// Every request a component answers, as it arrives.
+#[cfg(feature = "component")]
+#[derive(Clone, Copy)]
+pub enum TransactionKind {
+    List(ListRequest),
+    Boot(BootRequest),
+    Attest(AttestRequest),
+    Raw(RawPayload),
+}
+
+#[cfg(feature = "component")]
+impl TransactionKind {
+    /// Fails with `ErrorKind::Abort` if no request has `opcode`, and otherwise
+    /// as that request's `decode`.
+    pub fn decode(opcode: u8, bytes: &[u8]) -> Result<Self> {
+        match opcode {
+            ListRequest::OPCODE => ListRequest::decode(bytes).map(Self::List),
+            BootRequest::OPCODE => BootRequest::decode(bytes).map(Self::Boot),
+            AttestRequest::OPCODE => AttestRequest::decode(bytes).map(Self::Attest),
+            RawPayload::OPCODE => RawPayload::decode(bytes).map(Self::Raw),
+            _ => Err(ErrorKind::Abort),
+        }
+    }
+}
+
+/// Asks a component who it is.
+#[derive(Clone, Copy)]
+pub struct ListRequest;
+
+pub struct ListResponse {
+    /// `None` from components on protocol version 1, which answer without it.
+    pub id: Option<u32>,
+}
+
+impl Request for ListRequest {
+    const OPCODE: u8 = b'L';
+    #[cfg(feature = "ap")]
+    const REPEATABLE: bool = true;
+    type Response = ListResponse;
+
+    #[cfg(feature = "ap")]
+    fn decode_response(version: u8, bytes: &[u8]) -> Result<ListResponse> {
+        match version {
+            1 => Ok(ListResponse { id: None }),
+            _ => ListResponse::decode(bytes),
+        }
+    }
+}
+
+impl Codec for ListRequest {
+    fn encode(&self) -> [u8; MAX_TRANSACTION_SIZE] {
+        [0u8; MAX_TRANSACTION_SIZE]
+    }
+
+    fn decode(_: &[u8]) -> Result<Self> {
+        Ok(Self)
+    }
+}
+
+impl Codec for ListResponse {
+    fn encode(&self) -> [u8; MAX_TRANSACTION_SIZE] {
+        let mut bytes = [0u8; MAX_TRANSACTION_SIZE];
+        bytes[..4].copy_from_slice(&self.id.unwrap_or_default().to_le_bytes());
+        bytes
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self> {
+        let id = bytes.first_chunk().ok_or(ErrorKind::Underflow)?;
+        Ok(Self {
+            id: Some(u32::from_le_bytes(*id)),
+        })
+    }
+}
+
+/// Tells a component to boot.
+#[derive(Clone, Copy)]
+pub struct BootRequest;
+
+pub const MAX_BOOT_MESSAGE_SIZE: usize = MAX_TRANSACTION_SIZE - 1;
+
+/// `len || message`, the boot message cut short if it doesn't fit.
+pub struct BootResponse {
+    len: u8,
+    message: [u8; MAX_BOOT_MESSAGE_SIZE],
+}
+
+impl Request for BootRequest {
+    const OPCODE: u8 = b'B';
+    /// A component that got it has left the pre-boot loop.
+    #[cfg(feature = "ap")]
+    const REPEATABLE: bool = false;
+    type Response = BootResponse;
+}
+
+impl Codec for BootRequest {
+    fn encode(&self) -> [u8; MAX_TRANSACTION_SIZE] {
+        [0u8; MAX_TRANSACTION_SIZE]
+    }
+
+    fn decode(_: &[u8]) -> Result<Self> {
+        Ok(Self)
+    }
+}
+
+impl BootResponse {
+    #[cfg(feature = "component")]
+    pub fn new(message: &str) -> Self {
+        let message = message.as_bytes();
+        let len = message.len().min(MAX_BOOT_MESSAGE_SIZE);
+        let mut response = Self {
+            len: len as u8,
+            message: [0u8; MAX_BOOT_MESSAGE_SIZE],
+        };
+        response.message[..len].copy_from_slice(&message[..len]);
+        response
+    }
+
+    #[cfg(feature = "ap")]
+    pub fn message(&self) -> &[u8] {
+        &self.message[..self.len as usize]
+    }
+}
+
+impl Codec for BootResponse {
+    fn encode(&self) -> [u8; MAX_TRANSACTION_SIZE] {
+        let mut bytes = [0u8; MAX_TRANSACTION_SIZE];
+        bytes[0] = self.len;
+        bytes[1..].copy_from_slice(&self.message);
+        bytes
+    }
+
+    /// Fails with `ErrorKind::Overflow` if the length doesn't fit in a
+    /// response.
+    fn decode(bytes: &[u8]) -> Result<Self> {
+        let (len, message) = bytes.split_first().ok_or(ErrorKind::Underflow)?;
+        if *len as usize > MAX_BOOT_MESSAGE_SIZE {
+            return Err(ErrorKind::Overflow);
+        }
+
+        let mut response = Self {
+            len: *len,
+            message: [0u8; MAX_BOOT_MESSAGE_SIZE],
+        };
+        response.message[..*len as usize]
+            .copy_from_slice(message.get(..*len as usize).ok_or(ErrorKind::Underflow)?);
+        Ok(response)
+    }
+}
+
+/// Asks for the `index`th chunk of the component's attestation record, see
+/// `attestation`.
+#[derive(Clone, Copy)]
+pub struct AttestRequest {
+    pub index: u8,
+}
+
+pub struct AttestResponse {
+    pub chunk: [u8; MAX_TRANSACTION_SIZE],
+}
+
+impl Request for AttestRequest {
+    const OPCODE: u8 = b'A';
+    #[cfg(feature = "ap")]
+    const REPEATABLE: bool = true;
+    type Response = AttestResponse;
+}
+
+impl Codec for AttestRequest {
+    fn encode(&self) -> [u8; MAX_TRANSACTION_SIZE] {
+        let mut bytes = [0u8; MAX_TRANSACTION_SIZE];
+        bytes[0] = self.index;
+        bytes
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self> {
+        let index = *bytes.first().ok_or(ErrorKind::Underflow)?;
+        Ok(Self { index })
+    }
+}
+
+impl Codec for AttestResponse {
+    fn encode(&self) -> [u8; MAX_TRANSACTION_SIZE] {
+        self.chunk
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self> {
+        let chunk = bytes.first_chunk().ok_or(ErrorKind::Underflow)?;
+        Ok(Self { chunk: *chunk })
+    }
+}
+
+/// A `Raw` payload is `len || data || padding`, where every padding byte holds
+/// the number of padding bytes as in PKCS#7, so there is always at least one.
+pub const MAX_RAW_SIZE: usize = MAX_TRANSACTION_SIZE - 2;
+
+/// Up to `MAX_RAW_SIZE` bytes that come out of a `Raw` transaction exactly as
+/// they went in, trailing zeros included. Requests and responses alike, see
+/// `post_boot`.
+#[derive(Clone, Copy)]
+pub struct RawPayload {
+    len: u8,
+    data: [u8; MAX_RAW_SIZE],
+}
+
+impl RawPayload {
+    /// Fails with `ErrorKind::Overflow` if `bytes` doesn't fit in one payload.
+    pub fn new(bytes: &[u8]) -> Result<Self> {
+        let mut data = [0u8; MAX_RAW_SIZE];
+        data.get_mut(..bytes.len())
+            .ok_or(ErrorKind::Overflow)?
+            .copy_from_slice(bytes);
+        Ok(Self {
+            len: bytes.len() as u8,
+            data,
+        })
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data[..self.len as usize]
+    }
+}
+
+impl Request for RawPayload {
+    const OPCODE: u8 = b'R';
+    /// The mailbox takes a fragment at most once, see `post_boot`.
+    #[cfg(feature = "ap")]
+    const REPEATABLE: bool = false;
+    type Response = RawPayload;
+}
+
+impl Codec for RawPayload {
+    fn encode(&self) -> [u8; MAX_TRANSACTION_SIZE] {
+        let len = self.len as usize;
+        let padding = (MAX_TRANSACTION_SIZE - 1 - len) as u8;
+
+        let mut frame = [padding; MAX_TRANSACTION_SIZE];
+        frame[0] = self.len;
+        frame[1..=len].copy_from_slice(self.as_bytes());
+        frame
+    }
+
+    /// Fails with `ErrorKind::Overflow` if the length doesn't leave room for
+    /// padding, and with `ErrorKind::BadParam` if the padding doesn't match
+    /// the length.
+    fn decode(frame: &[u8]) -> Result<Self> {
+        let (len, rest) = frame
+            .split_first()
+            .filter(|_| frame.len() == MAX_TRANSACTION_SIZE)
+            .ok_or(ErrorKind::BadParam)?;
+        if *len as usize > MAX_RAW_SIZE {
+            return Err(ErrorKind::Overflow);
+        }
+
+        let (data, padding) = rest.split_at(*len as usize);
+        if padding.iter().any(|byte| *byte as usize != padding.len()) {
+            return Err(ErrorKind::BadParam);
+        }
+        Self::new(data)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_opcodes_are_unique() {
+        let opcodes = [
+            ListRequest::OPCODE,
+            BootRequest::OPCODE,
+            AttestRequest::OPCODE,
+            RawPayload::OPCODE,
+        ];
+        for (i, opcode) in opcodes.iter().enumerate() {
+            assert!(!opcodes[i + 1..].contains(opcode));
+        }
+    }
+
+    #[test]
+    fn test_request_round_trip() {
+        let bytes = AttestRequest { index: 3 }.encode();
+        assert!(matches!(
+            TransactionKind::decode(AttestRequest::OPCODE, &bytes),
+            Ok(TransactionKind::Attest(AttestRequest { index: 3 }))
+        ));
+        assert!(matches!(
+            TransactionKind::decode(ListRequest::OPCODE, &ListRequest.encode()),
+            Ok(TransactionKind::List(_))
+        ));
+        assert!(matches!(
+            TransactionKind::decode(b'?', &bytes),
+            Err(ErrorKind::Abort)
+        ));
+    }
+
+    #[test]
+    fn test_response_round_trip() {
+        let response = ListResponse {
+            id: Some(0x11111124),
+        }
+        .encode();
+        assert_eq!(
+            ListResponse::decode(&response).unwrap().id,
+            Some(0x11111124)
+        );
+        assert_eq!(ListRequest::decode_response(1, &response).unwrap().id, None);
+
+        let response = BootResponse::new("booted").encode();
+        assert_eq!(
+            BootResponse::decode(&response).unwrap().message(),
+            b"booted"
+        );
+
+        let long = [b'x'; MAX_TRANSACTION_SIZE];
+        let response = BootResponse::new(core::str::from_utf8(&long).unwrap()).encode();
+        assert_eq!(
+            BootResponse::decode(&response).unwrap().message(),
+            &long[..MAX_BOOT_MESSAGE_SIZE]
+        );
+
+        let mut response = response;
+        response[0] = MAX_TRANSACTION_SIZE as u8;
+        assert!(matches!(
+            BootResponse::decode(&response),
+            Err(ErrorKind::Overflow)
+        ));
+    }
+
+    #[test]
+    fn test_raw_payload_round_trip() {
+        let bytes: [u8; MAX_RAW_SIZE] = core::array::from_fn(|i| (i % 3) as u8);
+        for len in 0..=MAX_RAW_SIZE {
+            let encoded = RawPayload::new(&bytes[..len]).unwrap().encode();
+            assert_eq!(encoded[0] as usize, len);
+            assert_eq!(
+                RawPayload::decode(&encoded).unwrap().as_bytes(),
+                &bytes[..len]
+            );
+        }
+
+        assert!(matches!(
+            RawPayload::new(&[0; MAX_RAW_SIZE + 1]),
+            Err(ErrorKind::Overflow)
+        ));
+    }
+
+    #[test]
+    fn test_malformed_raw_payload_is_rejected() {
+        let encoded = RawPayload::new(b"hello").unwrap().encode();
+
+        let mut frame = encoded;
+        frame[0] = MAX_RAW_SIZE as u8 + 1;
+        assert!(matches!(
+            RawPayload::decode(&frame),
+            Err(ErrorKind::Overflow)
+        ));
+
+        // A length that disagrees with the padding, either way.
+        for len in [4, 6] {
+            let mut frame = encoded;
+            frame[0] = len;
+            assert!(matches!(
+                RawPayload::decode(&frame),
+                Err(ErrorKind::BadParam)
+            ));
+        }
+
+        let mut frame = encoded;
+        frame[MAX_TRANSACTION_SIZE - 1] = 0;
+        assert!(matches!(
+            RawPayload::decode(&frame),
+            Err(ErrorKind::BadParam)
+        ));
+        assert!(matches!(
+            RawPayload::decode(&encoded[..MAX_TRANSACTION_SIZE - 1]),
+            Err(ErrorKind::BadParam)
+        ));
+    }
+}
@@ -0,0 +1,247 @@
+//! In-memory stand-ins for the MAX78000 peripherals, so whole transactions can
+//! run on the host. Like on the real bus, the master and every slave are
+//! expected to run on their own thread.
+
+use std::{
+    collections::BTreeMap,
+    sync::{
+        mpsc::{channel, Receiver, RecvTimeoutError, Sender},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+    vec,
+    vec::Vec,
+};
+
+#[cfg(feature = "ap")]
+use crate::hal::I2cMaster;
+#[cfg(feature = "component")]
+use crate::hal::I2cSlave;
+use crate::{
+    eax::Block,
+    hal::{BlockCipher, Clock, Rng},
+    kdf::KEY_SIZE,
+};
+use aes::{
+    cipher::{generic_array::GenericArray, BlockEncrypt, KeyInit},
+    Aes128,
+};
+use max78000_hal::error::{ErrorKind, Result};
+
+/// How long the master waits on a slave that doesn't answer a read.
+const READ_TIMEOUT: Duration = Duration::from_secs(1);
+/// How long a slave waits on the master before reporting
+/// `ErrorKind::NoneAvailable`.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+enum Transfer {
+    Write(Vec<u8>),
+    Read(usize, Sender<Vec<u8>>),
+}
+
+/// A bus connecting one `MockMaster` to any number of `MockSlave`s. Once every
+/// handle to the bus is dropped, its slaves fail with `ErrorKind::Shutdown`.
+#[derive(Clone, Default)]
+pub struct MockBus {
+    slaves: Arc<Mutex<BTreeMap<usize, Sender<Transfer>>>>,
+}
+
+impl MockBus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn master(&self) -> MockMaster {
+        MockMaster { bus: self.clone() }
+    }
+
+    /// Attaches a slave at `address`, replacing whatever was there before.
+    pub fn attach(&self, address: usize) -> MockSlave {
+        let (sender, transfers) = channel();
+        self.slaves.lock().unwrap().insert(address, sender);
+        MockSlave { transfers }
+    }
+
+    /// Removes the slave at `address`, as if it was unplugged.
+    pub fn detach(&self, address: usize) {
+        self.slaves.lock().unwrap().remove(&address);
+    }
+}
+
+pub struct MockMaster {
+    bus: MockBus,
+}
+
+#[cfg(feature = "ap")]
+impl I2cMaster for MockMaster {
+    fn master_transaction(
+        &mut self,
+        address: usize,
+        rx: Option<&mut [u8]>,
+        tx: Option<&[u8]>,
+    ) -> Result<()> {
+        // Nobody acknowledging the address is reported as a `ComError`, just
+        // like on the real bus.
+        let slave = self
+            .bus
+            .slaves
+            .lock()
+            .unwrap()
+            .get(&address)
+            .cloned()
+            .ok_or(ErrorKind::ComError)?;
+
+        if let Some(tx) = tx {
+            slave
+                .send(Transfer::Write(tx.to_vec()))
+                .map_err(|_| ErrorKind::ComError)?;
+        }
+        if let Some(rx) = rx {
+            let (reply, response) = channel();
+            slave
+                .send(Transfer::Read(rx.len(), reply))
+                .map_err(|_| ErrorKind::ComError)?;
+            let bytes = response
+                .recv_timeout(READ_TIMEOUT)
+                .map_err(|_| ErrorKind::TimeOut)?;
+            rx.copy_from_slice(&bytes);
+        }
+
+        Ok(())
+    }
+}
+
+pub struct MockSlave {
+    transfers: Receiver<Transfer>,
+}
+
+impl MockSlave {
+    fn next_transfer(&mut self) -> Result<Transfer> {
+        self.transfers
+            .recv_timeout(POLL_INTERVAL)
+            .map_err(|err| match err {
+                RecvTimeoutError::Timeout => ErrorKind::NoneAvailable,
+                RecvTimeoutError::Disconnected => ErrorKind::Shutdown,
+            })
+    }
+}
+
+#[cfg(feature = "component")]
+impl I2cSlave for MockSlave {
+    fn slave_receive(&mut self, rx: &mut [u8]) -> Result<usize> {
+        match self.next_transfer()? {
+            Transfer::Write(bytes) => {
+                rx.get_mut(..bytes.len())
+                    .ok_or(ErrorKind::Overflow)?
+                    .copy_from_slice(&bytes);
+                Ok(bytes.len())
+            }
+            // The master read from us while we had nothing to say.
+            Transfer::Read(len, reply) => {
+                _ = reply.send(vec![0; len]);
+                Ok(0)
+            }
+        }
+    }
+
+    fn slave_send(&mut self, tx: &mut dyn Iterator<Item = u8>) -> Result<()> {
+        match self.next_transfer()? {
+            Transfer::Read(len, reply) => {
+                _ = reply.send(tx.take(len).collect());
+                Ok(())
+            }
+            Transfer::Write(_) => Err(ErrorKind::BadState),
+        }
+    }
+}
+
+/// Software AES-128.
+pub struct MockCipher(Aes128);
+
+impl MockCipher {
+    pub fn new() -> Self {
+        Self(Aes128::new(&GenericArray::default()))
+    }
+}
+
+impl Default for MockCipher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BlockCipher for MockCipher {
+    fn set_key(&mut self, key: &[u8; KEY_SIZE]) {
+        self.0 = Aes128::new(GenericArray::from_slice(key));
+    }
+
+    fn encrypt_block(&mut self, block: Block) -> Block {
+        let mut block = GenericArray::from(block);
+        self.0.encrypt_block(&mut block);
+        block.into()
+    }
+}
+
+/// A xorshift generator, seeded so failing tests can be replayed. Not for
+/// anything that needs real randomness.
+pub struct MockRng(u32);
+
+impl MockRng {
+    pub fn new(seed: u32) -> Self {
+        Self(seed | 1)
+    }
+}
+
+impl Rng for MockRng {
+    fn next_u32(&mut self) -> u32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        self.0
+    }
+}
+
+/// Wall-clock time since the clock was made.
+pub struct MockClock {
+    start: Instant,
+}
+
+impl MockClock {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for MockClock {
+    fn now_us(&mut self) -> u64 {
+        self.start.elapsed().as_micros() as u64
+    }
+
+    #[cfg(feature = "ap")]
+    fn delay_us(&mut self, us: u32) {
+        thread::sleep(Duration::from_micros(us as u64));
+    }
+}
+
+/// Only moves when the code under test waits.
+pub struct FakeClock(pub u64);
+
+impl Clock for FakeClock {
+    fn now_us(&mut self) -> u64 {
+        self.0
+    }
+
+    #[cfg(feature = "ap")]
+    fn delay_us(&mut self, us: u32) {
+        self.0 += us as u64;
+    }
+}
@@ -0,0 +1,604 @@
+//! Post-boot messaging behind the C `secure_send`/`secure_receive` API.
+//!
+//! Messages travel in `Raw` transactions, one fragment per transaction. The AP
+//! drives the bus, so it pushes messages to a component with `SEND`, pulls
+//! the component's messages fragment by fragment with `POLL` and confirms it
+//! has all of one with `DONE`. The component buffers both directions in a
+//! `Mailbox`.
+
+use core::ops::{Deref, DerefMut};
+
+use crate::{
+    ectf_params::{get_device, DeviceKind},
+    fragment::{Fragment, Reassembler, FRAGMENT_SIZE, MAX_PAYLOAD_SIZE},
+    hal::CycleCounter,
+    messages::RawPayload,
+};
+use max78000_hal::{
+    aes::AES,
+    error::{ErrorKind, Result},
+    i2c::{I2CPort1, I2C},
+    trng::TRNG,
+};
+
+#[cfg(feature = "component")]
+use crate::component;
+#[cfg(feature = "ap")]
+use crate::{
+    addressing::I2cAddress,
+    flash, fragment,
+    hal::{BlockCipher, Clock, I2cMaster, Rng},
+    kdf::KEY_SIZE,
+    secret::component_key,
+    security::{retried_master_transaction, secure_master_transaction, RetryPolicy},
+};
+
+/// Raw request: `op || fragment`, response: `status || fragment`. `POLL`
+/// carries the index of the fragment it asks for instead of a fragment, and
+/// `DONE` carries nothing.
+pub const MAX_MESSAGE_SIZE: usize = MAX_PAYLOAD_SIZE;
+
+const SEND: u8 = b'S';
+const POLL: u8 = b'P';
+const DONE: u8 = b'D';
+
+const OK: u8 = 0;
+const EMPTY: u8 = 1;
+const BUSY: u8 = 2;
+#[cfg(feature = "component")]
+const REFUSED: u8 = 3;
+
+/// How long the AP waits before polling a component with nothing to say
+/// again.
+#[cfg(feature = "ap")]
+const POLL_INTERVAL_US: u32 = 20_000;
+
+pub struct Peripherals {
+    pub i2c: I2C<I2CPort1>,
+    pub aes: AES,
+    pub trng: TRNG,
+    pub clock: CycleCounter,
+}
+
+static mut PERIPHERALS: Option<Peripherals> = None;
+static mut PERIPHERALS_REF: bool = false;
+
+pub struct PeripheralsRef<'a>(&'a mut Peripherals);
+
+impl<'a> Drop for PeripheralsRef<'a> {
+    fn drop(&mut self) {
+        unsafe { PERIPHERALS_REF = false };
+    }
+}
+
+impl<'a> Deref for PeripheralsRef<'a> {
+    type Target = Peripherals;
+    fn deref(&self) -> &Self::Target {
+        self.0
+    }
+}
+
+impl<'a> DerefMut for PeripheralsRef<'a> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.0
+    }
+}
+
+/// Hands the peripherals over to the post-boot API once the boot flow is done
+/// with them.
+pub fn store_peripherals(peripherals: Peripherals) {
+    unsafe { PERIPHERALS = Some(peripherals) };
+}
+
+pub fn get_mut_peripherals() -> Option<PeripheralsRef<'static>> {
+    if unsafe { PERIPHERALS_REF } {
+        None
+    } else {
+        let peripherals = unsafe { PERIPHERALS.as_mut()? };
+        unsafe { PERIPHERALS_REF = true };
+        Some(PeripheralsRef(peripherals))
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Message {
+    len: u8,
+    data: [u8; MAX_MESSAGE_SIZE],
+}
+
+impl Message {
+    /// Fails with `ErrorKind::Overflow` if `bytes` doesn't fit in one message.
+    pub fn new(bytes: &[u8]) -> Result<Self> {
+        let mut data = [0u8; MAX_MESSAGE_SIZE];
+        data.get_mut(..bytes.len())
+            .ok_or(ErrorKind::Overflow)?
+            .copy_from_slice(bytes);
+        Ok(Self {
+            len: bytes.len() as u8,
+            data,
+        })
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data[..self.len as usize]
+    }
+
+    /// Copies the message into `buffer` and returns its length.
+    pub fn copy_to(&self, buffer: &mut [u8]) -> Result<usize> {
+        buffer
+            .get_mut(..self.len as usize)
+            .ok_or(ErrorKind::Overflow)?
+            .copy_from_slice(self.as_bytes());
+        Ok(self.len as usize)
+    }
+
+    #[cfg(feature = "component")]
+    fn fragment(&self, index: u8) -> Option<Fragment> {
+        Fragment::of(self.as_bytes(), index)
+    }
+
+    #[cfg(feature = "ap")]
+    fn fragments(&self) -> impl Iterator<Item = Fragment> + '_ {
+        fragment::split(self.as_bytes())
+    }
+}
+
+/// `tag || body`, where `body` is at most a fragment.
+fn raw(tag: u8, body: &[u8]) -> RawPayload {
+    let mut bytes = [0u8; 1 + FRAGMENT_SIZE];
+    bytes[0] = tag;
+    bytes[1..=body.len()].copy_from_slice(body);
+    RawPayload::new(&bytes[..=body.len()]).unwrap()
+}
+
+fn status(status: u8) -> RawPayload {
+    raw(status, &[])
+}
+
+fn frame(tag: u8, fragment: &Fragment) -> RawPayload {
+    raw(tag, &fragment.encode())
+}
+
+#[cfg(feature = "ap")]
+fn poll(index: u8) -> RawPayload {
+    raw(POLL, &[index])
+}
+
+/// The component's side of post-boot messaging, holding at most one message
+/// in each direction until the other end picks it up.
+#[cfg(feature = "component")]
+pub struct Mailbox {
+    inbox: Option<Message>,
+    incoming: Reassembler,
+    outbox: Option<Message>,
+    /// The last fragment of the outbox went out at least once, so the AP may
+    /// confirm it with `DONE`.
+    picked_up: bool,
+}
+
+#[cfg(feature = "component")]
+impl Mailbox {
+    pub const fn new() -> Self {
+        Self {
+            inbox: None,
+            incoming: Reassembler::new(),
+            outbox: None,
+            picked_up: false,
+        }
+    }
+
+    fn deliver(&mut self, fragment: &[u8]) -> Result<()> {
+        let fragment = Fragment::decode(fragment)?;
+        if let Some(bytes) = self.incoming.add(&fragment)? {
+            self.inbox = Some(Message::new(bytes)?);
+        }
+        Ok(())
+    }
+
+    /// Answers a `Raw` request from the AP.
+    pub fn serve(&mut self, request: RawPayload) -> RawPayload {
+        match request.as_bytes() {
+            [SEND, ..] if self.inbox.is_some() => status(BUSY),
+            [SEND, fragment @ ..] => match self.deliver(fragment) {
+                Ok(()) => status(OK),
+                Err(_) => status(REFUSED),
+            },
+            // The outbox is only emptied once the AP confirms it has the whole
+            // message, so it can start over from the first fragment if a
+            // response gets lost.
+            [POLL, index] => match &self.outbox {
+                Some(message) => match message.fragment(*index) {
+                    Some(fragment) => {
+                        self.picked_up |= fragment.is_last();
+                        frame(OK, &fragment)
+                    }
+                    None => status(REFUSED),
+                },
+                None => status(EMPTY),
+            },
+            [DONE] if self.picked_up => {
+                self.outbox = None;
+                self.picked_up = false;
+                status(OK)
+            }
+            _ => status(REFUSED),
+        }
+    }
+
+    /// Fails with `ErrorKind::Busy` until the AP has picked up the last message.
+    pub fn post(&mut self, message: Message) -> Result<()> {
+        match self.outbox {
+            Some(_) => Err(ErrorKind::Busy),
+            None => {
+                self.outbox = Some(message);
+                self.picked_up = false;
+                Ok(())
+            }
+        }
+    }
+
+    pub fn take(&mut self) -> Option<Message> {
+        self.inbox.take()
+    }
+
+    /// A message from the AP is waiting to be taken.
+    pub fn is_receiving(&self) -> bool {
+        self.inbox.is_some()
+    }
+
+    /// A message for the AP is waiting to be picked up.
+    pub fn is_sending(&self) -> bool {
+        self.outbox.is_some()
+    }
+}
+
+/// Answer to `Raw` requests that arrive before the component has booted.
+#[cfg(feature = "component")]
+pub fn refuse() -> RawPayload {
+    status(REFUSED)
+}
+
+#[cfg(feature = "component")]
+static mut MAILBOX: Mailbox = Mailbox::new();
+
+#[cfg(feature = "component")]
+pub fn get_mut_mailbox() -> &'static mut Mailbox {
+    unsafe { &mut *core::ptr::addr_of_mut!(MAILBOX) }
+}
+
+/// The provisioned component answering at `address`.
+#[cfg(feature = "ap")]
+fn provisioned_component(address: u8) -> Result<u32> {
+    flash::get_component_ids()?
+        .iter()
+        .copied()
+        .find(|id| I2cAddress::try_from(*id).is_ok_and(|id| u8::from(id) == address))
+        .ok_or(ErrorKind::BadParam)
+}
+
+#[cfg(feature = "ap")]
+fn raw_transaction(address: u8, request: RawPayload) -> Result<RawPayload> {
+    let key = component_key(provisioned_component(address)?);
+    let Peripherals {
+        i2c,
+        aes,
+        trng,
+        clock,
+    } = &mut *get_mut_peripherals().ok_or(ErrorKind::Uninitialized)?;
+    let retried =
+        retried_master_transaction(i2c, aes, trng, clock, address as usize, &key, &request);
+    retried.result.map_err(ErrorKind::from)
+}
+
+/// Pulls the next message out of the component at `address`, polling it every
+/// `POLL_INTERVAL_US` while it has none. Fails with `ErrorKind::TimeOut` once
+/// it had nothing to say for `RetryPolicy::DEFAULT.deadline_us`.
+#[cfg(feature = "ap")]
+fn pull<B: I2cMaster, C: BlockCipher, R: Rng, K: Clock>(
+    i2c: &mut B,
+    aes: &mut C,
+    trng: &mut R,
+    clock: &mut K,
+    address: usize,
+    key: &[u8; KEY_SIZE],
+    buffer: &mut [u8],
+) -> Result<usize> {
+    let policy = RetryPolicy::DEFAULT;
+    let start = clock.now_us();
+    let mut incoming = Reassembler::new();
+    let mut index = 0;
+    loop {
+        // Asking for a fragment again changes nothing on the component, so
+        // unlike other `Raw` requests a `POLL` is retried even once it may
+        // have gone through.
+        let response = policy
+            .run(
+                clock,
+                || secure_master_transaction(i2c, aes, trng, address, key, &poll(index)),
+                || true,
+            )
+            .result?;
+        match response.as_bytes() {
+            [OK, fragment @ ..] => {
+                let fragment = Fragment::decode(fragment)?;
+                if let Some(bytes) = incoming.add(&fragment)? {
+                    let message = Message::new(bytes)?;
+                    let done = status(DONE);
+                    _ = retried_master_transaction(i2c, aes, trng, clock, address, key, &done);
+                    break message.copy_to(buffer);
+                }
+                index += 1;
+            }
+            [EMPTY] if index == 0 => {
+                if clock.now_us() - start + POLL_INTERVAL_US as u64 > policy.deadline_us as u64 {
+                    break Err(ErrorKind::TimeOut);
+                }
+                clock.delay_us(POLL_INTERVAL_US);
+            }
+            _ => break Err(ErrorKind::BadState),
+        }
+    }
+}
+
+/// Sends `bytes` to the component at `address` (AP), or waits for the AP to
+/// pick them up (component).
+#[cfg_attr(not(feature = "ap"), allow(unused_variables))]
+pub fn secure_send(address: u8, bytes: &[u8]) -> Result<()> {
+    let message = Message::new(bytes)?;
+    match get_device() {
+        #[cfg(feature = "ap")]
+        DeviceKind::ApplicationProcessor { .. } => {
+            for fragment in message.fragments() {
+                match raw_transaction(address, frame(SEND, &fragment))?.as_bytes() {
+                    [OK] => (),
+                    [BUSY] => return Err(ErrorKind::Busy),
+                    _ => return Err(ErrorKind::BadState),
+                }
+            }
+            Ok(())
+        }
+        #[cfg(feature = "component")]
+        DeviceKind::Component { .. } => component::send(message),
+        #[allow(unreachable_patterns)]
+        _ => Err(ErrorKind::NotSupported),
+    }
+}
+
+/// Receives the next message from the component at `address` (AP), or waits
+/// for the next message from the AP (component), and returns its length. The
+/// AP only waits so long, see `pull`.
+///
+/// The AP confirms every message it received in full, after which the
+/// component drops it. If that confirmation gets lost the message is still
+/// returned, and the component hands it out again on the next call rather than
+/// losing it.
+#[cfg_attr(not(feature = "ap"), allow(unused_variables))]
+pub fn secure_receive(address: u8, buffer: &mut [u8]) -> Result<usize> {
+    match get_device() {
+        #[cfg(feature = "ap")]
+        DeviceKind::ApplicationProcessor { .. } => {
+            let key = component_key(provisioned_component(address)?);
+            let Peripherals {
+                i2c,
+                aes,
+                trng,
+                clock,
+            } = &mut *get_mut_peripherals().ok_or(ErrorKind::Uninitialized)?;
+            pull(i2c, aes, trng, clock, address as usize, &key, buffer)
+        }
+        #[cfg(feature = "component")]
+        DeviceKind::Component { .. } => component::receive()?.copy_to(buffer),
+        #[allow(unreachable_patterns)]
+        _ => Err(ErrorKind::NotSupported),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        messages::{Request, TransactionKind},
+        mock::{FakeClock, MockBus, MockCipher, MockClock, MockRng, MockSlave},
+        security::{secure_slave_transaction, MAX_TRANSACTION_SIZE},
+    };
+    use std::thread;
+
+    const ADDRESS: usize = 0x24;
+    const KEY: [u8; KEY_SIZE] = [7; KEY_SIZE];
+
+    /// Answers the AP's `Raw` requests from `mailbox` until the bus goes away.
+    fn serve_mailbox(mut i2c: MockSlave, mut mailbox: Mailbox) -> Mailbox {
+        let mut trng = MockRng::new(2);
+        let mut clock = MockClock::new();
+        loop {
+            let result = secure_slave_transaction(
+                &mut i2c,
+                &mut MockCipher::new(),
+                &mut trng,
+                &mut clock,
+                &KEY,
+                |kind| match kind {
+                    TransactionKind::Raw(request) => request.respond(mailbox.serve(request)),
+                    _ => [0u8; MAX_TRANSACTION_SIZE],
+                },
+            );
+            if matches!(result, Err(ErrorKind::Shutdown)) {
+                break mailbox;
+            }
+        }
+    }
+
+    fn send(mailbox: &mut Mailbox, bytes: &[u8]) -> u8 {
+        let message = Message::new(bytes).unwrap();
+        let status = message
+            .fragments()
+            .map(|fragment| mailbox.serve(frame(SEND, &fragment)).as_bytes()[0])
+            .find(|status| *status != OK);
+        status.unwrap_or(OK)
+    }
+
+    fn receive(mailbox: &mut Mailbox) -> Option<Message> {
+        let mut incoming = Reassembler::new();
+        for index in 0.. {
+            let response = mailbox.serve(poll(index));
+            let [OK, fragment @ ..] = response.as_bytes() else {
+                return None;
+            };
+            let fragment = Fragment::decode(fragment).unwrap();
+            if let Some(bytes) = incoming.add(&fragment).unwrap() {
+                assert_eq!(mailbox.serve(raw(DONE, &[])).as_bytes(), [OK]);
+                return Some(Message::new(bytes).unwrap());
+            }
+        }
+        unreachable!()
+    }
+
+    #[test]
+    fn test_message_round_trip() {
+        let bytes: [u8; MAX_MESSAGE_SIZE] = core::array::from_fn(|i| i as u8);
+        let mut mailbox = Mailbox::new();
+        for len in [0, 5, MAX_MESSAGE_SIZE] {
+            assert_eq!(send(&mut mailbox, &bytes[..len]), OK);
+            assert_eq!(mailbox.take().unwrap().as_bytes(), &bytes[..len]);
+
+            mailbox.post(Message::new(&bytes[..len]).unwrap()).unwrap();
+            assert_eq!(receive(&mut mailbox).unwrap().as_bytes(), &bytes[..len]);
+        }
+
+        assert!(matches!(
+            Message::new(&[0; MAX_MESSAGE_SIZE + 1]),
+            Err(ErrorKind::Overflow)
+        ));
+    }
+
+    #[test]
+    fn test_malformed_length_is_rejected() {
+        let mut mailbox = Mailbox::new();
+        let mut fragment = Fragment::of(b"hello", 0).unwrap().encode();
+        fragment[2] = FRAGMENT_SIZE as u8;
+        assert_eq!(mailbox.serve(raw(SEND, &fragment)).as_bytes(), [REFUSED]);
+        assert!(!mailbox.is_receiving());
+    }
+
+    #[test]
+    fn test_interrupted_send_is_rejected() {
+        let bytes = [0x55; MAX_MESSAGE_SIZE];
+        let message = Message::new(&bytes).unwrap();
+        let mut mailbox = Mailbox::new();
+        let first = frame(SEND, &message.fragment(0).unwrap());
+        let third = frame(SEND, &message.fragment(2).unwrap());
+        assert_eq!(mailbox.serve(first).as_bytes()[0], OK);
+        assert_eq!(mailbox.serve(third).as_bytes()[0], REFUSED);
+        assert!(!mailbox.is_receiving());
+
+        assert_eq!(send(&mut mailbox, &bytes), OK);
+        assert_eq!(mailbox.take().unwrap().as_bytes(), bytes);
+    }
+
+    #[test]
+    fn test_send_restarts_at_first_fragment() {
+        let stale = Message::new(&[0x55; MAX_MESSAGE_SIZE]).unwrap();
+        let mut mailbox = Mailbox::new();
+        for index in 0..2 {
+            let fragment = frame(SEND, &stale.fragment(index).unwrap());
+            assert_eq!(mailbox.serve(fragment).as_bytes()[0], OK);
+        }
+
+        // The AP gave up on the stale message halfway, and starts over.
+        assert_eq!(send(&mut mailbox, b"fresh"), OK);
+        assert_eq!(mailbox.take().unwrap().as_bytes(), b"fresh");
+    }
+
+    #[test]
+    fn test_receive_times_out_on_quiet_component() {
+        let bus = MockBus::new();
+        let component = thread::spawn({
+            let i2c = bus.attach(ADDRESS);
+            move || serve_mailbox(i2c, Mailbox::new())
+        });
+
+        let mut clock = FakeClock(0);
+        let result = pull(
+            &mut bus.master(),
+            &mut MockCipher::new(),
+            &mut MockRng::new(1),
+            &mut clock,
+            ADDRESS,
+            &KEY,
+            &mut [0u8; MAX_MESSAGE_SIZE],
+        );
+        drop(bus);
+        component.join().unwrap();
+
+        assert!(matches!(result, Err(ErrorKind::TimeOut)));
+        assert!(clock.0 <= RetryPolicy::DEFAULT.deadline_us as u64);
+    }
+
+    #[test]
+    fn test_receive_pulls_whole_message() {
+        let bytes: [u8; MAX_MESSAGE_SIZE] = core::array::from_fn(|i| i as u8);
+        let mut mailbox = Mailbox::new();
+        mailbox.post(Message::new(&bytes).unwrap()).unwrap();
+        let bus = MockBus::new();
+        let component = thread::spawn({
+            let i2c = bus.attach(ADDRESS);
+            move || serve_mailbox(i2c, mailbox)
+        });
+
+        let mut buffer = [0u8; MAX_MESSAGE_SIZE];
+        let result = pull(
+            &mut bus.master(),
+            &mut MockCipher::new(),
+            &mut MockRng::new(1),
+            &mut FakeClock(0),
+            ADDRESS,
+            &KEY,
+            &mut buffer,
+        );
+        drop(bus);
+        let mailbox = component.join().unwrap();
+
+        assert_eq!(result.unwrap(), MAX_MESSAGE_SIZE);
+        assert_eq!(buffer, bytes);
+        assert!(!mailbox.is_sending());
+    }
+
+    #[test]
+    fn test_mailbox() {
+        let mut mailbox = Mailbox::new();
+        assert_eq!(mailbox.serve(poll(0)).as_bytes()[0], EMPTY);
+
+        assert_eq!(send(&mut mailbox, b"to component"), OK);
+        assert_eq!(send(&mut mailbox, b"to component"), BUSY);
+        assert_eq!(mailbox.take().unwrap().as_bytes(), b"to component");
+        assert!(mailbox.take().is_none());
+
+        mailbox.post(Message::new(b"to ap").unwrap()).unwrap();
+        assert!(matches!(
+            mailbox.post(Message::new(b"again").unwrap()),
+            Err(ErrorKind::Busy)
+        ));
+        assert_eq!(mailbox.serve(poll(1)).as_bytes()[0], REFUSED);
+        assert!(mailbox.is_sending());
+        assert_eq!(receive(&mut mailbox).unwrap().as_bytes(), b"to ap");
+        assert_eq!(mailbox.serve(poll(0)).as_bytes()[0], EMPTY);
+    }
+
+    #[test]
+    fn test_message_is_kept_until_confirmed() {
+        let mut mailbox = Mailbox::new();
+        mailbox.post(Message::new(b"to ap").unwrap()).unwrap();
+        assert_eq!(mailbox.serve(raw(DONE, &[])).as_bytes(), [REFUSED]);
+
+        // The AP lost the response carrying the only fragment, and asks again.
+        let first = mailbox.serve(poll(0));
+        assert_eq!(first.as_bytes()[0], OK);
+        assert_eq!(mailbox.serve(poll(0)).as_bytes(), first.as_bytes());
+        assert!(mailbox.is_sending());
+
+        assert_eq!(mailbox.serve(raw(DONE, &[])).as_bytes(), [OK]);
+        assert!(!mailbox.is_sending());
+        assert_eq!(mailbox.serve(poll(0)).as_bytes()[0], EMPTY);
+        assert_eq!(mailbox.serve(raw(DONE, &[])).as_bytes(), [REFUSED]);
+    }
+}
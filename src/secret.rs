@@ -1 +1,19 @@
-pub const SECRET: [u8; 16] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15];
+use crate::kdf::KEY_SIZE;
+#[cfg(feature = "ap")]
+use crate::kdf::{self, HASH_SIZE};
+
+// `DEPLOYMENT_SECRET` and the PIN and token hashes for the AP, and
+// `COMPONENT_ID` and `COMPONENT_KEY` for components, generated by `build.rs` from the deployment
+// secrets file.
+include!(concat!(env!("OUT_DIR"), "/secret.rs"));
+
+#[cfg(feature = "ap")]
+pub fn component_key(component_id: u32) -> [u8; KEY_SIZE] {
+    kdf::component_key(&DEPLOYMENT_SECRET, component_id)
+}
+
+/// Hashes a PIN or token the way `build.rs` hashed the AP's own.
+#[cfg(feature = "ap")]
+pub fn secret_hash(secret: &[u8]) -> [u8; HASH_SIZE] {
+    kdf::secret_hash(&kdf::secret_salt(&DEPLOYMENT_SECRET), secret)
+}
@@ -1,319 +1,1496 @@
-use core::array::IntoIter;
-
-use crate::{host_msg, secret::SECRET};
-use max78000_hal::{
-    aes::{AESIterExt, CipherType, Key, AES},
-    error::{ErrorKind, Result},
-    i2c::{I2CPort1, I2C},
-    trng::TRNG,
-};
+#[cfg(feature = "ap")]
+use core::cell::Cell;
 
-const KEY: Key = Key::Bits128(&SECRET);
+#[cfg(feature = "ap")]
+use crate::hal::I2cMaster;
+use crate::{
+    checksum::{self, checksum, CHECKSUM_SIZE},
+    eax::{self, BLOCK_SIZE, TAG_SIZE},
+    hal::{BlockCipher, Clock, Rng},
+    kdf::KEY_SIZE,
+    messages::{RawPayload, Request},
+};
+#[cfg(feature = "component")]
+use crate::{hal::I2cSlave, host_msg, messages::TransactionKind};
+use max78000_hal::error::{ErrorKind, Result};
 
-const BLOCK_SIZE: usize = 16;
 pub const MAX_TRANSACTION_SIZE: usize = BLOCK_SIZE * 4;
 
-const OVERALL_TRANSACTION_SIZE: usize = MAX_TRANSACTION_SIZE + BLOCK_SIZE;
+/// Sent in the clear, but authenticated, in front of every frame after the
+/// handshake. See `Session::header`.
+const HEADER_SIZE: usize = BLOCK_SIZE;
+const SESSION_ID_SIZE: usize = 8;
 
-pub enum TransactionKind {
-    List,
-    Boot,
-    Attest,
-    Raw([u8; MAX_TRANSACTION_SIZE]),
-}
+/// kind block + payload block, see `MasterChannel`
+const V1_REQUEST_SIZE: usize = BLOCK_SIZE + MAX_TRANSACTION_SIZE;
+/// opcode + request
+const V2_REQUEST_SIZE: usize = 1 + MAX_TRANSACTION_SIZE;
+
+/// header + request, as laid out by the oldest version, which has the largest.
+/// See `Session::request_size`.
+const OVERALL_TRANSACTION_SIZE: usize = HEADER_SIZE + V1_REQUEST_SIZE;
+
+// Every frame on the bus ends in a checksum, see `checksum`.
+const NONCE_SIZE: usize = BLOCK_SIZE;
+/// master nonce + master versions
+#[cfg(feature = "component")]
+const CHALLENGE_FRAME_SIZE: usize = NONCE_SIZE + VERSIONS_SIZE + CHECKSUM_SIZE;
+/// slave nonce + slave versions + proof that the slave holds the key
+#[cfg(feature = "ap")]
+const ANSWER_FRAME_SIZE: usize = NONCE_SIZE + VERSIONS_SIZE + TAG_SIZE + CHECKSUM_SIZE;
+/// What the slave's proof vouches for, see `answer_transcript`.
+const TRANSCRIPT_SIZE: usize = NONCE_SIZE + 2 * VERSIONS_SIZE;
+/// proof that the master holds the key + `MasterChannel` + tag, the largest a
+/// request frame gets. See `Session::request_frame_size`.
+#[cfg(feature = "component")]
+const REQUEST_FRAME_SIZE: usize = TAG_SIZE + OVERALL_TRANSACTION_SIZE + TAG_SIZE + CHECKSUM_SIZE;
+/// header + encrypted response + tag
+#[cfg(feature = "ap")]
+const RESPONSE_FRAME_SIZE: usize = HEADER_SIZE + MAX_TRANSACTION_SIZE + TAG_SIZE + CHECKSUM_SIZE;
 
+// Everything in a transaction is keyed by the same key, so each use gets its
+// own prefix byte to keep a value made for one purpose from being accepted for
+// another.
+const TO_SLAVE: u8 = b'M';
+const TO_MASTER: u8 = b'S';
+const MASTER_PROOF: u8 = b'm';
+const SLAVE_PROOF: u8 = b's';
+
+/// The oldest and newest protocol version this firmware speaks. Both sides
+/// offer their range in the handshake, and the session runs on the newest
+/// version they have in common. Version 2 packs requests tighter than version
+/// 1, see `MasterChannel`, and has components report their ID, see
+/// `ListRequest`.
+const MIN_VERSION: u8 = 1;
+const MAX_VERSION: u8 = 2;
+const VERSIONS_SIZE: usize = 2;
+
+/// Carries a request behind the session header, laid out as the session's
+/// version has it, see `messages` for the requests themselves:
+/// - 1: `kind block || payload block`. The kind block starts with the opcode,
+///   `Raw` payloads fill the payload block, and any other request follows its
+///   opcode in the kind block.
+/// - 2: `opcode || request`.
 struct MasterChannel {
-    trng_key: u8,
+    #[cfg(feature = "component")]
     kind: TransactionKind,
 }
 
+/// Whether version 1 carries requests with `opcode` in the payload block.
+fn in_payload_block(opcode: u8) -> bool {
+    opcode == RawPayload::OPCODE
+}
+
 impl MasterChannel {
-    fn into_slave(kind: TransactionKind, rand: u8) -> IntoIter<u8, OVERALL_TRANSACTION_SIZE> {
+    /// Returns the channel, of which the session's version uses
+    /// `HEADER_SIZE + session.request_size()` bytes.
+    #[cfg(feature = "ap")]
+    fn into_slave<Req: Request>(
+        request: &Req,
+        session: &Session,
+    ) -> [u8; OVERALL_TRANSACTION_SIZE] {
         let mut data = [0u8; OVERALL_TRANSACTION_SIZE];
-        data[0] = rand;
-        match kind {
-            TransactionKind::List => data[1] = rand ^ b'L',
-            TransactionKind::Boot => data[1] = rand ^ b'B',
-            TransactionKind::Attest => data[1] = rand ^ b'A',
-            TransactionKind::Raw(raw) => {
-                data[1] = rand ^ b'R';
-                data.iter_mut()
-                    .skip(BLOCK_SIZE)
-                    .zip(raw.into_iter().map(|raw| raw ^ rand))
-                    .for_each(|(data, raw)| *data = raw)
-            }
-        }
-        data[2] = rand;
-        data[3] = rand;
+        data[..HEADER_SIZE].copy_from_slice(&session.header());
 
-        data.into_iter()
+        let encoded = request.encode();
+        let (opcode, body) = data[HEADER_SIZE..].split_first_mut().unwrap();
+        *opcode = Req::OPCODE;
+        match session.version {
+            1 if in_payload_block(Req::OPCODE) => body[BLOCK_SIZE - 1..].copy_from_slice(&encoded),
+            1 => body[..BLOCK_SIZE - 1].copy_from_slice(&encoded[..BLOCK_SIZE - 1]),
+            _ => body[..MAX_TRANSACTION_SIZE].copy_from_slice(&encoded),
+        }
+        data
     }
 
-    fn from_master<Iter>(bytes: &mut Iter) -> Option<Self>
-    where
-        Iter: Iterator<Item = u8>,
-    {
-        // for some reason, `bytes.next()? ^ trng_key` crashes rust-analyzer,
-        // so here they are seperated.
-        let (trng_key, kind) = (bytes.next()?, bytes.next()?);
-        let kind = kind ^ trng_key;
-
-        let kind = match kind {
-            b'L' => TransactionKind::List,
-            b'B' => TransactionKind::Boot,
-            b'A' => TransactionKind::Attest,
-            b'R' => {
-                let mut data = [0u8; MAX_TRANSACTION_SIZE];
-                data.iter_mut()
-                    .zip(bytes.skip(BLOCK_SIZE - 2))
-                    .for_each(|(data, byte)| *data = byte);
-                TransactionKind::Raw(data)
-            }
+    /// Fails with `ErrorKind::BadState` if the frame belongs to another session
+    /// or was already accepted in this one, and otherwise as
+    /// `TransactionKind::decode`.
+    #[cfg(feature = "component")]
+    fn from_master(bytes: &[u8], session: &Session) -> Result<Self> {
+        let (header, body) = bytes.split_at(HEADER_SIZE.min(bytes.len()));
+        session.accept(header)?;
 
-            _ => return None,
+        let (opcode, body) = body.split_first().ok_or(ErrorKind::Abort)?;
+        let request = match session.version {
+            1 if in_payload_block(*opcode) => body.get(BLOCK_SIZE - 1..),
+            1 => body.get(..BLOCK_SIZE - 1),
+            _ => Some(body),
         };
+        let kind = TransactionKind::decode(*opcode, request.ok_or(ErrorKind::Abort)?)?;
+        Ok(Self { kind })
+    }
+}
+
+/// The protocol versions one side speaks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Versions {
+    pub min: u8,
+    pub max: u8,
+}
+
+impl Versions {
+    const OURS: Self = Self {
+        min: MIN_VERSION,
+        max: MAX_VERSION,
+    };
+
+    fn encode(&self) -> [u8; VERSIONS_SIZE] {
+        [self.min, self.max]
+    }
+
+    fn decode(bytes: &[u8]) -> Self {
+        Self {
+            min: bytes[0],
+            max: bytes[1],
+        }
+    }
+
+    /// The newest version both sides speak, if there is one.
+    fn negotiate(&self, peer: &Self) -> Option<u8> {
+        let version = self.max.min(peer.max);
+        (version >= self.min.max(peer.min)).then_some(version)
+    }
+}
+
+/// `slave nonce || master versions || slave versions`. The slave proves it
+/// holds the key over all of it, so neither side's offer can be changed on the
+/// way to force a downgrade.
+fn answer_transcript(
+    slave_nonce: &[u8],
+    master: &Versions,
+    slave: &Versions,
+) -> [u8; TRANSCRIPT_SIZE] {
+    let mut transcript = [0u8; TRANSCRIPT_SIZE];
+    transcript[..NONCE_SIZE].copy_from_slice(slave_nonce);
+    transcript[NONCE_SIZE..NONCE_SIZE + VERSIONS_SIZE].copy_from_slice(&master.encode());
+    transcript[NONCE_SIZE + VERSIONS_SIZE..].copy_from_slice(&slave.encode());
+    transcript
+}
+
+/// The nonces both sides contributed during the handshake and the protocol
+/// version they agreed on. Every transaction runs its own handshake and carries
+/// a single exchange, so the fresh nonces are what keep frames from an earlier
+/// transaction from being accepted in this one.
+struct Session {
+    master_nonce: [u8; NONCE_SIZE],
+    slave_nonce: [u8; NONCE_SIZE],
+    version: u8,
+}
+
+impl Session {
+    /// `session id || reserved || version || reserved`, where the session id
+    /// mixes both handshake nonces so that neither side alone picks it.
+    fn header(&self) -> [u8; HEADER_SIZE] {
+        const HALF: usize = SESSION_ID_SIZE / 2;
+        const VERSION_OFFSET: usize = SESSION_ID_SIZE + 4;
+
+        let mut header = [0u8; HEADER_SIZE];
+        header[..HALF].copy_from_slice(&self.master_nonce[..HALF]);
+        header[HALF..SESSION_ID_SIZE].copy_from_slice(&self.slave_nonce[..HALF]);
+        header[VERSION_OFFSET] = self.version;
+        header
+    }
+
+    /// Only the header of this session is accepted, anything else is a replay
+    /// of a frame from an earlier one.
+    fn accept(&self, header: &[u8]) -> Result<()> {
+        match header == self.header() {
+            true => Ok(()),
+            false => Err(ErrorKind::BadState),
+        }
+    }
+
+    /// How many bytes the request takes behind the header in this version.
+    fn request_size(&self) -> usize {
+        match self.version {
+            1 => V1_REQUEST_SIZE,
+            _ => V2_REQUEST_SIZE,
+        }
+    }
+
+    #[cfg(feature = "component")]
+    fn request_frame_size(&self) -> usize {
+        REQUEST_FRAME_SIZE - V1_REQUEST_SIZE + self.request_size()
+    }
+}
+
+/// The header is unique for every frame, so it doubles as the EAX nonce.
+fn frame_nonce(direction: u8, header: &[u8]) -> [u8; 1 + HEADER_SIZE] {
+    let mut nonce = [direction; 1 + HEADER_SIZE];
+    nonce[1..].copy_from_slice(header);
+    nonce
+}
+
+fn random_nonce<R: Rng>(trng: &mut R) -> [u8; NONCE_SIZE] {
+    let mut nonce = [0u8; NONCE_SIZE];
+    nonce
+        .chunks_mut(4)
+        .for_each(|chunk| chunk.copy_from_slice(&trng.next_u32().to_le_bytes()));
+    nonce
+}
+
+/// Proves knowledge of the key by authenticating the peer's `challenge` together
+/// with our own nonce, and anything else that comes with it.
+fn prove<C: BlockCipher>(aes: &mut C, role: u8, challenge: &[u8], own: &[u8]) -> [u8; TAG_SIZE] {
+    let mut nonce = [role; 1 + NONCE_SIZE];
+    nonce[1..].copy_from_slice(challenge);
+    eax::seal(aes, &nonce, own, &mut [])
+}
 
-        Some(Self { trng_key, kind })
+fn verify<C: BlockCipher>(
+    aes: &mut C,
+    role: u8,
+    challenge: &[u8],
+    peer: &[u8],
+    proof: &[u8],
+) -> Result<()> {
+    let mut nonce = [role; 1 + NONCE_SIZE];
+    nonce[1..].copy_from_slice(challenge);
+    eax::open(aes, &nonce, peer, &mut [], proof)
+}
+
+/// Sends `bytes` followed by their checksum, a block at a time.
+#[cfg(feature = "ap")]
+fn master_send<B, Iter>(i2c: &mut B, address: usize, bytes: Iter) -> Result<()>
+where
+    B: I2cMaster,
+    Iter: Iterator<Item = u8> + Clone,
+{
+    let mut buffer = [0u8; BLOCK_SIZE];
+    let mut len = 0;
+    for byte in bytes.clone().chain(checksum(bytes)) {
+        buffer[len] = byte;
+        len += 1;
+        if len == BLOCK_SIZE {
+            i2c.master_transaction(address, None, Some(&buffer))?;
+            len = 0;
+        }
+    }
+
+    match len {
+        0 => Ok(()),
+        _ => i2c.master_transaction(address, None, Some(&buffer[..len])),
     }
 }
 
-pub fn secure_master_transaction(
-    i2c: &mut I2C<I2CPort1>,
-    aes: &mut AES,
-    trng: &mut TRNG,
+/// Challenges the slave at `address` and returns the session once the slave
+/// has proven it holds the key. The master's own proof is sent with the
+/// request.
+///
+/// Fails with `MasterError::Incompatible` if the slave speaks no protocol
+/// version we do.
+#[cfg(feature = "ap")]
+fn master_handshake<B: I2cMaster, C: BlockCipher, R: Rng>(
+    i2c: &mut B,
+    aes: &mut C,
+    trng: &mut R,
     address: usize,
-    kind: TransactionKind,
-) -> Result<[u8; MAX_TRANSACTION_SIZE]> {
-    let random = trng.get_trng_data() as u8;
-    let random = 0;
-    aes.set_key(&KEY);
-
-    MasterChannel::into_slave(kind, random)
-        // .cipher(aes, CipherType::Encrypt)
-        .array_chunks()
-        .try_for_each(|buffer: [u8; BLOCK_SIZE]| {
-            i2c.master_transaction(address, None, Some(&buffer))
-        })?;
+) -> core::result::Result<Session, MasterError> {
+    let master_nonce = random_nonce(trng);
+    let challenge = master_nonce.into_iter().chain(Versions::OURS.encode());
+    master_send(i2c, address, challenge)?;
 
-    let mut rx_buffer = [0u8; MAX_TRANSACTION_SIZE];
-    i2c.master_transaction(address, Some(&mut rx_buffer), None)?;
+    let mut answer = [0u8; ANSWER_FRAME_SIZE];
+    i2c.master_transaction(address, Some(&mut answer), None)?;
 
-    rx_buffer
-        .clone()
-        .into_iter()
-        // .cipher(aes, CipherType::Decrypt)
-        .map(|byte| byte ^ random)
-        .zip(rx_buffer.iter_mut())
-        .for_each(|(cipher, plain)| *plain = cipher);
+    let (slave_nonce, rest) = checksum::verify(&mut answer)?.split_at(NONCE_SIZE);
+    let (versions, proof) = rest.split_at(VERSIONS_SIZE);
+    let versions = Versions::decode(versions);
+    let transcript = answer_transcript(slave_nonce, &Versions::OURS, &versions);
+    verify(aes, SLAVE_PROOF, &master_nonce, &transcript, proof)?;
 
-    Ok(rx_buffer)
+    let version = Versions::OURS
+        .negotiate(&versions)
+        .ok_or(MasterError::Incompatible {
+            ours: Versions::OURS,
+            slave: versions,
+        })?;
+
+    let mut session = Session {
+        master_nonce,
+        slave_nonce: [0u8; NONCE_SIZE],
+        version,
+    };
+    session.slave_nonce.copy_from_slice(slave_nonce);
+    Ok(session)
 }
 
-pub fn secure_slave_transaction<TXFunc>(
-    i2c: &mut I2C<I2CPort1>,
-    aes: &mut AES,
-    mon: TXFunc,
-) -> Result<()>
+/// `secure_master_transaction` under `RetryPolicy::DEFAULT`. Once an attempt
+/// got as far as sending a request that isn't `Request::REPEATABLE`, it isn't
+/// sent again, since the slave may have acted on it even if its response got
+/// lost on the way back.
+///
+/// Fails as `secure_master_transaction`, except that a slave speaking no
+/// protocol version we do fails with `MasterError::Incompatible`.
+#[cfg(feature = "ap")]
+pub fn retried_master_transaction<B, C, R, K, Req>(
+    i2c: &mut B,
+    aes: &mut C,
+    trng: &mut R,
+    clock: &mut K,
+    address: usize,
+    key: &[u8; KEY_SIZE],
+    request: &Req,
+) -> Retried<Req::Response, MasterError>
 where
-    TXFunc: FnOnce(TransactionKind) -> [u8; MAX_TRANSACTION_SIZE],
+    B: I2cMaster,
+    C: BlockCipher,
+    R: Rng,
+    K: Clock,
+    Req: Request,
 {
-    aes.set_key(&KEY);
+    let sent = Cell::new(false);
+    RetryPolicy::DEFAULT.run(
+        clock,
+        || tracked_master_transaction(i2c, aes, trng, address, key, request, &sent),
+        || Req::REPEATABLE || !sent.get(),
+    )
+}
+
+/// Mutually authenticates with the slave at `address`, then sends it `request`
+/// and returns its response. `key` is the slave's own key, see
+/// `secret::component_key`.
+///
+/// Fails with `ErrorKind::ComError` if a frame was corrupted on the bus, with
+/// `ErrorKind::Invalid` if the slave cannot prove it holds `key` or if any frame
+/// was tampered with, with `ErrorKind::NotSupported` if the slave speaks no
+/// protocol version we do, and with `ErrorKind::BadState` if the slave answers
+/// with a replayed response. A response that doesn't decode fails as its
+/// `decode`.
+#[cfg(feature = "ap")]
+pub fn secure_master_transaction<B: I2cMaster, C: BlockCipher, R: Rng, Req: Request>(
+    i2c: &mut B,
+    aes: &mut C,
+    trng: &mut R,
+    address: usize,
+    key: &[u8; KEY_SIZE],
+    request: &Req,
+) -> Result<Req::Response> {
+    tracked_master_transaction(i2c, aes, trng, address, key, request, &Cell::new(false))
+        .map_err(ErrorKind::from)
+}
+
+/// `secure_master_transaction` that sets `sent` right before the request goes
+/// out on the bus, so `retried_master_transaction` knows whether the slave may
+/// have seen it.
+#[cfg(feature = "ap")]
+fn tracked_master_transaction<B: I2cMaster, C: BlockCipher, R: Rng, Req: Request>(
+    i2c: &mut B,
+    aes: &mut C,
+    trng: &mut R,
+    address: usize,
+    key: &[u8; KEY_SIZE],
+    request: &Req,
+    sent: &Cell<bool>,
+) -> core::result::Result<Req::Response, MasterError> {
+    aes.set_key(key);
+    let session = master_handshake(i2c, aes, trng, address)?;
+
+    let proof = prove(
+        aes,
+        MASTER_PROOF,
+        &session.slave_nonce,
+        &session.master_nonce,
+    );
+    let mut channel = MasterChannel::into_slave(request, &session);
+    let channel = &mut channel[..HEADER_SIZE + session.request_size()];
+    let (header, body) = channel.split_at_mut(HEADER_SIZE);
+    let tag = eax::seal(aes, &frame_nonce(TO_SLAVE, header), header, body);
+    sent.set(true);
+    let frame = proof.iter().chain(&*channel).chain(&tag).copied();
+    master_send(i2c, address, frame)?;
+
+    let mut rx_buffer = [0u8; RESPONSE_FRAME_SIZE];
+    i2c.master_transaction(address, Some(&mut rx_buffer), None)?;
+
+    let response = open_response(aes, &session, &mut rx_buffer)?;
+    Ok(Req::decode_response(session.version, &response)?)
+}
 
-    let mut rx_buffer = [0; 64];
+/// Checks the slave's response frame for this exchange and decrypts it.
+#[cfg(feature = "ap")]
+fn open_response<C: BlockCipher>(
+    aes: &mut C,
+    session: &Session,
+    frame: &mut [u8; RESPONSE_FRAME_SIZE],
+) -> Result<[u8; MAX_TRANSACTION_SIZE]> {
+    let (header, rest) = checksum::verify(frame)?.split_at_mut(HEADER_SIZE);
+    let (response, tag) = rest.split_at_mut(MAX_TRANSACTION_SIZE);
+    eax::open(aes, &frame_nonce(TO_MASTER, header), header, response, tag)?;
+    session.accept(header)?;
+
+    let mut plain = [0u8; MAX_TRANSACTION_SIZE];
+    plain.copy_from_slice(response);
+    Ok(plain)
+}
+
+/// How the master deals with a slave that doesn't answer, or whose frames get
+/// corrupted on the way.
+#[derive(Clone, Copy)]
+#[cfg(feature = "ap")]
+pub struct RetryPolicy {
+    /// Attempts after the first one.
+    pub retries: u8,
+    /// Wait before the first retry, doubled before every further one up to
+    /// `max_backoff_us`.
+    pub backoff_us: u32,
+    pub max_backoff_us: u32,
+    /// No retry is started that would begin later than this after the first
+    /// attempt.
+    pub deadline_us: u32,
+}
+
+/// How a transaction with a slave failed, as `ErrorKind` for the most part.
+/// A slave that speaks no protocol version we do comes with both sides'
+/// versions, for the caller to report.
+#[cfg(feature = "ap")]
+#[derive(Clone, Copy, Debug)]
+pub enum MasterError {
+    Incompatible { ours: Versions, slave: Versions },
+    Other(ErrorKind),
+}
+
+#[cfg(feature = "ap")]
+impl From<ErrorKind> for MasterError {
+    fn from(err: ErrorKind) -> Self {
+        Self::Other(err)
+    }
+}
+
+#[cfg(feature = "ap")]
+impl From<MasterError> for ErrorKind {
+    fn from(err: MasterError) -> Self {
+        match err {
+            MasterError::Incompatible { .. } => ErrorKind::NotSupported,
+            MasterError::Other(err) => err,
+        }
+    }
+}
+
+/// The outcome of a retried transaction, and how many retries it took to get
+/// there.
+#[cfg(feature = "ap")]
+pub struct Retried<T, E = ErrorKind> {
+    pub result: core::result::Result<T, E>,
+    pub retries: u8,
+}
+
+#[cfg(feature = "ap")]
+impl RetryPolicy {
+    /// Backs off for longer than a slave takes to give up on a broken frame,
+    /// see `resync`.
+    pub const DEFAULT: Self = Self {
+        retries: 3,
+        backoff_us: 50_000,
+        max_backoff_us: 100_000,
+        deadline_us: 500_000,
+    };
+
+    /// Runs `transaction`, typically a `secure_master_transaction`, again
+    /// while it fails with `ErrorKind::ComError` or `ErrorKind::TimeOut` and
+    /// `retryable` still holds. Anything else, most of all a slave failing to
+    /// authenticate, is final.
+    pub fn run<T, E, K>(
+        &self,
+        clock: &mut K,
+        mut transaction: impl FnMut() -> core::result::Result<T, E>,
+        retryable: impl Fn() -> bool,
+    ) -> Retried<T, E>
+    where
+        E: Copy + Into<ErrorKind>,
+        K: Clock,
+    {
+        let start = clock.now_us();
+        let mut backoff = self.backoff_us;
+        let mut retries = 0;
+        loop {
+            match transaction() {
+                Err(err)
+                    if matches!(
+                        Into::<ErrorKind>::into(err),
+                        ErrorKind::ComError | ErrorKind::TimeOut
+                    ) && retries < self.retries
+                        && retryable()
+                        && clock.now_us() - start + backoff as u64 <= self.deadline_us as u64 =>
+                {
+                    clock.delay_us(backoff);
+                    backoff = backoff.saturating_mul(2).min(self.max_backoff_us);
+                    retries += 1;
+                }
+                result => break Retried { result, retries },
+            }
+        }
+    }
+}
+
+/// Longest the master may go quiet in the middle of a frame, counted from the
+/// frame's first byte on.
+#[cfg(feature = "component")]
+const INTER_BYTE_TIMEOUT_US: u64 = 25_000;
+/// Longest a whole frame may take, counted from its first byte for the
+/// challenge, and from when we start waiting for any later frame.
+#[cfg(feature = "component")]
+const FRAME_TIMEOUT_US: u64 = 100_000;
+
+/// Fills `rx_buffer` from the master's writes. With `idle`, fails with
+/// `ErrorKind::NoneAvailable` until the master starts writing.
+///
+/// Once a frame is under way, fails with `ErrorKind::TimeOut` if the master
+/// stalls, and throws away whatever it still sends of the frame, see `resync`.
+#[cfg(feature = "component")]
+fn slave_receive<B: I2cSlave, K: Clock>(
+    i2c: &mut B,
+    clock: &mut K,
+    rx_buffer: &mut [u8],
+    idle: bool,
+) -> Result<()> {
+    let mut start = clock.now_us();
+    let mut last = start;
     let mut rx_index = 0;
-    let MasterChannel { trng_key, kind } = MasterChannel::from_master(
-        &mut loop {
-            match i2c.slave_manual_pulling(&mut [].into_iter()) {
-                Ok(rx_iter) => {
-                    host_msg!(Debug, "Stop");
-                    if rx_index >= rx_buffer.len() {
-                        continue;
-                    }
-                    rx_iter.for_each(|b| {
-                        rx_buffer[rx_index] = b;
-                        rx_index += 1;
-                    });
-                    break Ok(rx_buffer.into_iter());
+    while rx_index < rx_buffer.len() {
+        let received = i2c.slave_receive(&mut rx_buffer[rx_index..]);
+        let now = clock.now_us();
+        match received {
+            Ok(0) | Err(ErrorKind::NoneAvailable) if idle && rx_index == 0 => {
+                return Err(ErrorKind::NoneAvailable);
+            }
+            Ok(0) | Err(ErrorKind::NoneAvailable) => {
+                let stalled = rx_index > 0 && now - last > INTER_BYTE_TIMEOUT_US;
+                if stalled || now - start > FRAME_TIMEOUT_US {
+                    host_msg!(Debug, "rx timeout after {} bytes", rx_index);
+                    resync(i2c, clock);
+                    return Err(ErrorKind::TimeOut);
                 }
-                Err(ErrorKind::Underflow) => {
-                    if rx_index != 0 || i2c.transaction_buffer.0 != 0 {
-                        host_msg!(
-                            Debug,
-                            "Underflow: {}, {}",
-                            rx_index,
-                            i2c.transaction_buffer.0
-                        );
-                    }
+            }
+            Ok(len) => {
+                if idle && rx_index == 0 {
+                    start = now;
                 }
-                Err(ErrorKind::NoneAvailable) => (),
-                Err(err) => {
-                    host_msg!(Error, "rx_err: {:?}", err);
-                    break Err(err);
+                rx_index += len;
+                last = now;
+            }
+            Err(err) => {
+                host_msg!(Error, "rx_err: {:?}", err);
+                if matches!(err, ErrorKind::Overflow) {
+                    resync(i2c, clock);
                 }
+                return Err(err);
             }
-        }?
-        .into_iter()
-        .inspect(|x| host_msg!(Debug, "f{}", x)), // .cipher(aes, CipherType::Decrypt),
-    )
-    .ok_or(ErrorKind::Abort)?;
-
-    host_msg!(Debug, "pass");
+        }
+    }
 
-    let mut resp_iter = mon(kind)
-        .into_iter()
-        .inspect(|x| host_msg!(Debug, "s{}", x))
-        .map(|byte| byte ^ trng_key)
-        .chain([0].into_iter().cycle());
-    // .cipher(aes, CipherType::Encrypt);
+    Ok(())
+}
 
-    // while let Err(_) = i2c.slave_manual_pulling(&mut [].into_iter()) {}
+/// Drops the master's writes until it has been quiet for
+/// `INTER_BYTE_TIMEOUT_US`, so the rest of a broken frame isn't taken for the
+/// start of the next one.
+#[cfg(feature = "component")]
+fn resync<B: I2cSlave, K: Clock>(i2c: &mut B, clock: &mut K) {
+    let mut scratch = [0u8; BLOCK_SIZE];
+    let mut last = clock.now_us();
+    loop {
+        let received = i2c.slave_receive(&mut scratch);
+        let now = clock.now_us();
+        match received {
+            Ok(0) | Err(ErrorKind::NoneAvailable) => {
+                if now - last > INTER_BYTE_TIMEOUT_US {
+                    return;
+                }
+            }
+            Ok(_) | Err(ErrorKind::Overflow) => last = now,
+            Err(_) => return,
+        }
+    }
+}
 
+/// Sends `bytes` followed by their checksum. Fails with `ErrorKind::TimeOut`
+/// if the master doesn't come to read them.
+#[cfg(feature = "component")]
+fn slave_send<B, K, Iter>(i2c: &mut B, clock: &mut K, bytes: Iter) -> Result<()>
+where
+    B: I2cSlave,
+    K: Clock,
+    Iter: Iterator<Item = u8> + Clone,
+{
+    // Frames carry their own lengths, the zeros only answer a master that
+    // reads past the end of one.
+    let mut tx_iter = bytes
+        .clone()
+        .chain(checksum(bytes))
+        .chain([0].into_iter().cycle());
+    let start = clock.now_us();
     loop {
-        match i2c.slave_manual_pulling(&mut resp_iter) {
-            Ok(_) => break Ok(()),
-            // Err(ErrorKind::NoResponse) => (),
-            Err(ErrorKind::NoneAvailable) => (),
+        match i2c.slave_send(&mut tx_iter) {
+            Ok(()) => break Ok(()),
+            Err(ErrorKind::NoneAvailable) if clock.now_us() - start <= FRAME_TIMEOUT_US => (),
+            Err(ErrorKind::NoneAvailable) => {
+                host_msg!(Debug, "tx timeout");
+                break Err(ErrorKind::TimeOut);
+            }
             Err(err) => {
-                host_msg!(Error, "1 {:?}", err);
+                host_msg!(Error, "tx_err: {:?}", err);
                 break Err(err);
             }
         }
     }
 }
 
+/// Answers the master's challenge, and only services the request with `mon`
+/// once the master has proven it holds `key` for this session. `mon` answers
+/// with the encoded response, see `Request::respond`.
+///
+/// Fails with `ErrorKind::NoneAvailable` if the master hasn't started a
+/// transaction, and with `ErrorKind::TimeOut` if it stalls in the middle of
+/// one. Fails with `ErrorKind::ComError` if a frame was corrupted on the bus,
+/// with `ErrorKind::NotSupported` if the master speaks no protocol version we
+/// do, and with `ErrorKind::Invalid` if the master cannot prove it holds `key`
+/// or if the request was tampered with. Either way the slave is ready for the next
+/// transaction afterwards.
+#[cfg(feature = "component")]
+pub fn secure_slave_transaction<B, C, R, K, TXFunc>(
+    i2c: &mut B,
+    aes: &mut C,
+    trng: &mut R,
+    clock: &mut K,
+    key: &[u8; KEY_SIZE],
+    mon: TXFunc,
+) -> Result<()>
+where
+    B: I2cSlave,
+    C: BlockCipher,
+    R: Rng,
+    K: Clock,
+    TXFunc: FnOnce(TransactionKind) -> [u8; MAX_TRANSACTION_SIZE],
+{
+    slave_transaction(i2c, aes, trng, clock, key, Versions::OURS, mon)
+}
+
+/// `secure_slave_transaction` offering `ours` in the handshake.
+#[cfg(feature = "component")]
+fn slave_transaction<B, C, R, K, TXFunc>(
+    i2c: &mut B,
+    aes: &mut C,
+    trng: &mut R,
+    clock: &mut K,
+    key: &[u8; KEY_SIZE],
+    ours: Versions,
+    mon: TXFunc,
+) -> Result<()>
+where
+    B: I2cSlave,
+    C: BlockCipher,
+    R: Rng,
+    K: Clock,
+    TXFunc: FnOnce(TransactionKind) -> [u8; MAX_TRANSACTION_SIZE],
+{
+    aes.set_key(key);
+
+    let mut challenge = [0u8; CHALLENGE_FRAME_SIZE];
+    slave_receive(i2c, clock, &mut challenge, true)?;
+    let (master_nonce, versions) = checksum::verify(&mut challenge)?.split_at(NONCE_SIZE);
+    let versions = Versions::decode(versions);
+
+    let slave_nonce = random_nonce(trng);
+    let transcript = answer_transcript(&slave_nonce, &versions, &ours);
+    let proof = prove(aes, SLAVE_PROOF, master_nonce, &transcript);
+    // Answer even without a version in common, so the master can tell why we
+    // drop out.
+    let answer = slave_nonce.into_iter().chain(ours.encode()).chain(proof);
+    slave_send(i2c, clock, answer)?;
+
+    let mut session = Session {
+        master_nonce: [0u8; NONCE_SIZE],
+        slave_nonce,
+        version: versions.negotiate(&ours).ok_or(ErrorKind::NotSupported)?,
+    };
+    session.master_nonce.copy_from_slice(master_nonce);
+
+    let mut rx_buffer = [0u8; REQUEST_FRAME_SIZE];
+    let rx_buffer = &mut rx_buffer[..session.request_frame_size()];
+    slave_receive(i2c, clock, rx_buffer, false)?;
+
+    let (proof, rest) = checksum::verify(rx_buffer)?.split_at_mut(TAG_SIZE);
+    let (channel, tag) = rest.split_at_mut(HEADER_SIZE + session.request_size());
+    let (header, body) = channel.split_at_mut(HEADER_SIZE);
+    eax::open(aes, &frame_nonce(TO_SLAVE, header), header, body, tag)?;
+    let MasterChannel { kind } = MasterChannel::from_master(channel, &session)?;
+    verify(
+        aes,
+        MASTER_PROOF,
+        &session.slave_nonce,
+        &session.master_nonce,
+        proof,
+    )?;
+
+    let header = session.header();
+    let mut response = mon(kind);
+    let tag = eax::seal(
+        aes,
+        &frame_nonce(TO_MASTER, &header),
+        &header,
+        &mut response,
+    );
+    slave_send(i2c, clock, header.into_iter().chain(response).chain(tag))
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
-    extern crate std;
-    use std::vec::Vec;
+    use crate::{
+        messages::{AttestRequest, BootRequest, Codec, ListRequest, ListResponse, MAX_RAW_SIZE},
+        mock::{FakeClock, MockBus, MockCipher, MockClock, MockMaster, MockRng, MockSlave},
+    };
+    use std::{thread, time::Duration, vec::Vec};
+
+    const ADDRESS: usize = 0x24;
+    const KEY: [u8; KEY_SIZE] = [0x42; KEY_SIZE];
+    const RESPONSE: &[u8] = b"response";
+
+    fn response() -> [u8; MAX_TRANSACTION_SIZE] {
+        RawPayload::new(RESPONSE).unwrap().encode()
+    }
+
+    /// Serves the next transaction the master starts on `i2c`, and returns
+    /// what the slave was asked to service. A slave serving several
+    /// transactions has to keep passing the same `trng`, like the real one,
+    /// or it repeats its nonces.
+    fn serve<B: I2cSlave>(
+        i2c: &mut B,
+        trng: &mut MockRng,
+        key: &[u8; KEY_SIZE],
+    ) -> Result<Option<TransactionKind>> {
+        serve_offering(i2c, trng, key, Versions::OURS)
+    }
+
+    /// `serve`, offering `versions` in the handshake.
+    fn serve_offering<B: I2cSlave>(
+        i2c: &mut B,
+        trng: &mut MockRng,
+        key: &[u8; KEY_SIZE],
+        versions: Versions,
+    ) -> Result<Option<TransactionKind>> {
+        let mut clock = MockClock::new();
+        loop {
+            let mut serviced = None;
+            match slave_transaction(
+                i2c,
+                &mut MockCipher::new(),
+                trng,
+                &mut clock,
+                key,
+                versions,
+                |kind| {
+                    serviced = Some(kind);
+                    response()
+                },
+            ) {
+                Err(ErrorKind::NoneAvailable) => (),
+                result => break result.map(|()| serviced),
+            }
+        }
+    }
+
+    /// Runs one transaction over a `MockBus` and returns what the master got
+    /// back and what the slave was asked to service.
+    fn transact<Req: Request>(
+        master_key: [u8; KEY_SIZE],
+        slave_key: [u8; KEY_SIZE],
+        request: Req,
+    ) -> (Result<Req::Response>, Result<Option<TransactionKind>>) {
+        transact_offering(master_key, slave_key, Versions::OURS, request)
+    }
+
+    /// `transact`, with the slave offering `versions` in the handshake.
+    fn transact_offering<Req: Request>(
+        master_key: [u8; KEY_SIZE],
+        slave_key: [u8; KEY_SIZE],
+        versions: Versions,
+        request: Req,
+    ) -> (Result<Req::Response>, Result<Option<TransactionKind>>) {
+        let bus = MockBus::new();
+        let mut i2c = bus.attach(ADDRESS);
+        let slave = thread::spawn(move || {
+            serve_offering(&mut i2c, &mut MockRng::new(2), &slave_key, versions)
+        });
+
+        let response = secure_master_transaction(
+            &mut bus.master(),
+            &mut MockCipher::new(),
+            &mut MockRng::new(1),
+            ADDRESS,
+            &master_key,
+            &request,
+        );
+        drop(bus);
+        (response, slave.join().unwrap())
+    }
+
+    fn session() -> Session {
+        Session {
+            master_nonce: [0x11; NONCE_SIZE],
+            slave_nonce: [0x22; NONCE_SIZE],
+            version: MAX_VERSION,
+        }
+    }
+
+    /// What the master sends of `request` in `session`.
+    fn channel<Req: Request>(request: &Req, session: &Session) -> Vec<u8> {
+        let channel = MasterChannel::into_slave(request, session);
+        channel[..HEADER_SIZE + session.request_size()].to_vec()
+    }
+
+    fn expect_body<Req: Request>(request: Req, opcode: u8) {
+        let session = session();
+        let host_channel = channel(&request, &session);
+
+        let mut expected = [0u8; 1 + MAX_TRANSACTION_SIZE];
+        expected[0] = opcode;
+        assert_eq!(host_channel[..HEADER_SIZE], session.header());
+        assert_eq!(host_channel[HEADER_SIZE..], expected);
+    }
 
     #[test]
     fn test_making_master_channel_list() {
-        for trng_key in 0..=255 {
-            let host_channel: Vec<u8> = MasterChannel::into_slave(TransactionKind::List, trng_key)
-                .take(16)
-                .collect();
-
-            assert_eq!(
-                host_channel,
-                &[
-                    trng_key,
-                    b'L' ^ trng_key,
-                    trng_key,
-                    trng_key,
-                    0,
-                    0,
-                    0,
-                    0,
-                    0,
-                    0,
-                    0,
-                    0,
-                    0,
-                    0,
-                    0,
-                    0
-                ]
-            );
-        }
+        expect_body(ListRequest, b'L');
     }
 
     #[test]
     fn test_making_master_channel_boot() {
-        for trng_key in 0..=255 {
-            let host_channel: Vec<u8> = MasterChannel::into_slave(TransactionKind::Boot, trng_key)
-                .take(16)
-                .collect();
-
-            assert_eq!(
-                host_channel,
-                &[
-                    trng_key,
-                    b'B' ^ trng_key,
-                    trng_key,
-                    trng_key,
-                    0,
-                    0,
-                    0,
-                    0,
-                    0,
-                    0,
-                    0,
-                    0,
-                    0,
-                    0,
-                    0,
-                    0
-                ]
-            );
-        }
+        expect_body(BootRequest, b'B');
     }
 
     #[test]
     fn test_making_master_channel_attest() {
-        for trng_key in 0..=255 {
-            let host_channel: Vec<u8> =
-                MasterChannel::into_slave(TransactionKind::Attest, trng_key)
-                    .take(16)
-                    .collect();
-
-            assert_eq!(
-                host_channel,
-                &[
-                    trng_key,
-                    b'A' ^ trng_key,
-                    trng_key,
-                    trng_key,
-                    0,
-                    0,
-                    0,
-                    0,
-                    0,
-                    0,
-                    0,
-                    0,
-                    0,
-                    0,
-                    0,
-                    0
-                ]
-            );
+        let session = session();
+        let host_channel = channel(&AttestRequest { index: 3 }, &session);
+
+        assert_eq!(host_channel[HEADER_SIZE..HEADER_SIZE + 2], [b'A', 3]);
+        match MasterChannel::from_master(&host_channel, &session) {
+            Ok(MasterChannel {
+                kind: TransactionKind::Attest(request),
+            }) => assert_eq!(request.index, 3),
+            _ => panic!("attest channel did not round trip"),
         }
     }
 
     #[test]
     fn test_making_master_channel_raw() {
-        for trng_key in 0..=255 {
-            let host_channel: Vec<u8> = MasterChannel::into_slave(
-                TransactionKind::Raw([trng_key; MAX_TRANSACTION_SIZE]),
-                trng_key,
+        let session = session();
+        for byte in 0..=255 {
+            let raw = RawPayload::new(&[byte; MAX_RAW_SIZE]).unwrap();
+            let host_channel = channel(&raw, &session);
+
+            assert_eq!(host_channel[HEADER_SIZE], b'R');
+            assert_eq!(host_channel[HEADER_SIZE + 1..], raw.encode());
+
+            match MasterChannel::from_master(&host_channel, &session) {
+                Ok(MasterChannel {
+                    kind: TransactionKind::Raw(raw),
+                }) => assert_eq!(raw.as_bytes(), [byte; MAX_RAW_SIZE]),
+                _ => panic!("raw channel did not round trip"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_unknown_kind_is_rejected() {
+        let session = session();
+        let mut host_channel = channel(&ListRequest, &session);
+        host_channel[HEADER_SIZE] = b'?';
+        assert!(matches!(
+            MasterChannel::from_master(&host_channel, &session),
+            Err(ErrorKind::Abort)
+        ));
+    }
+
+    #[test]
+    fn test_channel_from_other_session_is_rejected() {
+        let host_channel = channel(&BootRequest, &session());
+
+        let mut session = session();
+        session.slave_nonce = [0x33; NONCE_SIZE];
+        assert!(matches!(
+            MasterChannel::from_master(&host_channel, &session),
+            Err(ErrorKind::BadState)
+        ));
+    }
+
+    #[test]
+    fn test_newest_common_version_is_negotiated() {
+        let versions = |min, max| Versions { min, max };
+        assert_eq!(versions(1, 3).negotiate(&versions(2, 5)), Some(3));
+        assert_eq!(versions(2, 5).negotiate(&versions(1, 3)), Some(3));
+        assert_eq!(versions(1, 1).negotiate(&versions(1, 1)), Some(1));
+        assert_eq!(versions(1, 2).negotiate(&versions(3, 4)), None);
+        assert_eq!(versions(3, 4).negotiate(&versions(1, 2)), None);
+    }
+
+    #[test]
+    fn test_version_1_layout() {
+        let mut session = session();
+        session.version = 1;
+
+        let attest = channel(&AttestRequest { index: 3 }, &session);
+        assert_eq!(
+            attest.len(),
+            HEADER_SIZE + BLOCK_SIZE + MAX_TRANSACTION_SIZE
+        );
+        assert_eq!(attest[HEADER_SIZE..HEADER_SIZE + 2], [b'A', 3]);
+        assert!(attest[HEADER_SIZE + 2..].iter().all(|byte| *byte == 0));
+
+        let raw = RawPayload::new(b"raw").unwrap();
+        let host_channel = channel(&raw, &session);
+        assert_eq!(host_channel[HEADER_SIZE], b'R');
+        assert_eq!(host_channel[HEADER_SIZE + BLOCK_SIZE..], raw.encode());
+        match MasterChannel::from_master(&host_channel, &session) {
+            Ok(MasterChannel {
+                kind: TransactionKind::Raw(raw),
+            }) => assert_eq!(raw.as_bytes(), b"raw"),
+            _ => panic!("version 1 raw channel did not round trip"),
+        }
+    }
+
+    #[test]
+    fn test_version_1_slave_is_served() {
+        let old = Versions { min: 1, max: 1 };
+
+        let (response, serviced) = transact_offering(KEY, KEY, old, AttestRequest { index: 3 });
+        assert!(response.is_ok());
+        assert!(matches!(
+            serviced,
+            Ok(Some(TransactionKind::Attest(AttestRequest { index: 3 })))
+        ));
+
+        let (response, serviced) = transact_offering(KEY, KEY, old, ListRequest);
+        assert!(matches!(response, Ok(ListResponse { id: None })));
+        assert!(matches!(serviced, Ok(Some(TransactionKind::List(_)))));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_slave_without_common_version_is_rejected() {
+        let newer = Versions {
+            min: MAX_VERSION + 1,
+            max: MAX_VERSION + 2,
+        };
+        let bus = MockBus::new();
+        let mut i2c = bus.attach(ADDRESS);
+        let slave =
+            thread::spawn(move || serve_offering(&mut i2c, &mut MockRng::new(2), &KEY, newer));
+
+        // Reporting the versions is up to the caller.
+        let (retried, output) = crate::host_msg::capture(|| {
+            retried_master_transaction(
+                &mut bus.master(),
+                &mut MockCipher::new(),
+                &mut MockRng::new(1),
+                &mut MockClock::new(),
+                ADDRESS,
+                &KEY,
+                &ListRequest,
             )
-            // one extra byte to test trng_key ^ trng_key for the raw bytes
-            .take(17)
-            .collect();
-
-            assert_eq!(
-                host_channel,
-                &[
-                    trng_key,
-                    b'R' ^ trng_key,
-                    trng_key,
-                    trng_key,
-                    0,
-                    0,
-                    0,
-                    0,
-                    0,
-                    0,
-                    0,
-                    0,
-                    0,
-                    0,
-                    0,
-                    0,
-                    0
-                ]
-            );
+        });
+        drop(bus);
+
+        assert!(matches!(
+            retried.result,
+            Err(MasterError::Incompatible { ours, slave: offered })
+                if ours == Versions::OURS && offered == newer
+        ));
+        assert_eq!(retried.retries, 0);
+        assert!(matches!(
+            slave.join().unwrap(),
+            Err(ErrorKind::NotSupported)
+        ));
+        assert_eq!(output, "");
+
+        let (response, _) = transact_offering(KEY, KEY, newer, ListRequest);
+        assert!(matches!(response, Err(ErrorKind::NotSupported)));
+    }
+
+    #[test]
+    fn test_session_header_carries_version() {
+        let mut session = session();
+        let header = session.header();
+        session.version += 1;
+        assert!(matches!(session.accept(&header), Err(ErrorKind::BadState)));
+    }
+
+    #[test]
+    fn test_replayed_response_is_rejected() {
+        let mut session = session();
+        let mut aes = MockCipher::new();
+        aes.set_key(&KEY);
+
+        let mut frame = response_frame(&KEY, &session);
+        session.master_nonce = [0x33; NONCE_SIZE];
+        assert!(matches!(
+            open_response(&mut aes, &session, &mut frame),
+            Err(ErrorKind::BadState)
+        ));
+    }
+
+    /// Keeps a copy of every write the master makes.
+    struct RecordingMaster {
+        i2c: MockMaster,
+        writes: Vec<Vec<u8>>,
+    }
+
+    impl I2cMaster for RecordingMaster {
+        fn master_transaction(
+            &mut self,
+            address: usize,
+            rx: Option<&mut [u8]>,
+            tx: Option<&[u8]>,
+        ) -> Result<()> {
+            self.writes.extend(tx.map(<[u8]>::to_vec));
+            self.i2c.master_transaction(address, rx, tx)
+        }
+    }
+
+    #[test]
+    fn test_replayed_request_is_rejected() {
+        let bus = MockBus::new();
+        let mut i2c = bus.attach(ADDRESS);
+        let slave = thread::spawn(move || {
+            let mut trng = MockRng::new(2);
+            let first = serve(&mut i2c, &mut trng, &KEY);
+            (first, serve(&mut i2c, &mut trng, &KEY))
+        });
+
+        let mut master = RecordingMaster {
+            i2c: bus.master(),
+            writes: Vec::new(),
+        };
+        let response = secure_master_transaction(
+            &mut master,
+            &mut MockCipher::new(),
+            &mut MockRng::new(1),
+            ADDRESS,
+            &KEY,
+            &BootRequest,
+        );
+        assert!(response.is_ok());
+
+        // Every write of the first transaction again, reading the slave's
+        // answer in between like the master did.
+        let (challenge, request) = master
+            .writes
+            .split_at(CHALLENGE_FRAME_SIZE.div_ceil(BLOCK_SIZE));
+        let mut i2c = bus.master();
+        for write in challenge {
+            assert!(i2c.master_transaction(ADDRESS, None, Some(write)).is_ok());
+        }
+        let mut answer = [0u8; ANSWER_FRAME_SIZE];
+        assert!(i2c
+            .master_transaction(ADDRESS, Some(&mut answer), None)
+            .is_ok());
+        for write in request {
+            assert!(i2c.master_transaction(ADDRESS, None, Some(write)).is_ok());
+        }
+        drop(bus);
+
+        let (first, replayed) = slave.join().unwrap();
+        assert!(matches!(first, Ok(Some(TransactionKind::Boot(_)))));
+        assert!(matches!(replayed, Err(ErrorKind::BadState)));
+    }
+
+    /// The response frame a slave holding `key` sends in `session`.
+    fn response_frame(key: &[u8; KEY_SIZE], session: &Session) -> [u8; RESPONSE_FRAME_SIZE] {
+        let mut aes = MockCipher::new();
+        aes.set_key(key);
+        let header = session.header();
+        let mut response = response();
+        let tag = eax::seal(
+            &mut aes,
+            &frame_nonce(TO_MASTER, &header),
+            &header,
+            &mut response,
+        );
+
+        let bytes = header.into_iter().chain(response).chain(tag);
+        let mut frame = [0u8; RESPONSE_FRAME_SIZE];
+        for (byte, value) in frame.iter_mut().zip(bytes.clone().chain(checksum(bytes))) {
+            *byte = value;
         }
+        frame
+    }
+
+    #[test]
+    fn test_corrupted_response_is_told_apart_from_forged_one() {
+        let session = session();
+        let mut aes = MockCipher::new();
+        aes.set_key(&KEY);
+
+        let mut frame = response_frame(&KEY, &session);
+        assert!(
+            matches!(open_response(&mut aes, &session, &mut frame), Ok(plain) if plain == response())
+        );
+
+        let mut frame = response_frame(&KEY, &session);
+        frame[HEADER_SIZE] ^= 1;
+        assert!(matches!(
+            open_response(&mut aes, &session, &mut frame),
+            Err(ErrorKind::ComError)
+        ));
+
+        let mut frame = response_frame(&[0x43; KEY_SIZE], &session);
+        assert!(matches!(
+            open_response(&mut aes, &session, &mut frame),
+            Err(ErrorKind::Invalid)
+        ));
+    }
+
+    #[test]
+    fn test_flipped_bit_with_valid_checksum_is_rejected() {
+        let session = session();
+        let mut aes = MockCipher::new();
+        aes.set_key(&KEY);
+
+        let mut frame = response_frame(&KEY, &session);
+        frame[HEADER_SIZE] ^= 1;
+        let (bytes, sum) = frame.split_at_mut(RESPONSE_FRAME_SIZE - CHECKSUM_SIZE);
+        sum.copy_from_slice(&checksum(bytes.iter().copied()));
+        assert!(matches!(
+            open_response(&mut aes, &session, &mut frame),
+            Err(ErrorKind::Invalid)
+        ));
+    }
+
+    /// Runs a transaction under `policy` that fails with `failures` in turn
+    /// before it succeeds.
+    fn retry(policy: &RetryPolicy, failures: &[ErrorKind]) -> Retried<()> {
+        let mut failures = failures.iter().copied();
+        policy.run(
+            &mut FakeClock(0),
+            || match failures.next() {
+                Some(err) => Err(err),
+                None => Ok(()),
+            },
+            || true,
+        )
+    }
+
+    #[test]
+    fn test_transient_failures_are_retried() {
+        let policy = RetryPolicy::DEFAULT;
+        assert!(matches!(
+            retry(&policy, &[]),
+            Retried {
+                result: Ok(()),
+                retries: 0
+            }
+        ));
+        assert!(matches!(
+            retry(&policy, &[ErrorKind::ComError, ErrorKind::TimeOut]),
+            Retried {
+                result: Ok(()),
+                retries: 2
+            }
+        ));
+        assert!(matches!(
+            retry(&policy, &[ErrorKind::ComError; 4]),
+            Retried {
+                result: Err(ErrorKind::ComError),
+                retries: 3
+            }
+        ));
+    }
+
+    #[test]
+    fn test_authentication_failure_is_final() {
+        assert!(matches!(
+            retry(&RetryPolicy::DEFAULT, &[ErrorKind::Invalid]),
+            Retried {
+                result: Err(ErrorKind::Invalid),
+                retries: 0
+            }
+        ));
+    }
+
+    #[test]
+    fn test_retries_stop_at_the_deadline() {
+        let policy = RetryPolicy {
+            retries: u8::MAX,
+            backoff_us: 10,
+            max_backoff_us: 40,
+            deadline_us: 100,
+        };
+        // Waits 10, 20 and 40 microseconds, waiting another 40 would start the
+        // next attempt past the deadline.
+        assert!(matches!(
+            retry(&policy, &[ErrorKind::TimeOut; 10]),
+            Retried {
+                result: Err(ErrorKind::TimeOut),
+                retries: 3
+            }
+        ));
+    }
+
+    #[test]
+    fn test_transaction_round_trip() {
+        let raw = RawPayload::new(&[7; MAX_RAW_SIZE]).unwrap();
+        let (response, serviced) = transact(KEY, KEY, raw);
+
+        assert!(matches!(response, Ok(response) if response.as_bytes() == RESPONSE));
+        match serviced {
+            Ok(Some(TransactionKind::Raw(raw))) => assert_eq!(raw.as_bytes(), [7; MAX_RAW_SIZE]),
+            _ => panic!("slave did not service the request"),
+        }
+    }
+
+    #[test]
+    fn test_slave_with_wrong_key_is_rejected() {
+        let (response, serviced) = transact(KEY, [0x43; KEY_SIZE], BootRequest);
+
+        assert!(matches!(response, Err(ErrorKind::Invalid)));
+        assert!(matches!(serviced, Err(ErrorKind::Shutdown)));
+    }
+
+    /// Runs the master's side of a transaction without checking the slave's
+    /// answer, sealing `ListRequest` under `key` and passing the master's proof
+    /// through `tamper` right before sending the request. Returns how the slave
+    /// took it, and whether it got as far as servicing the request.
+    fn forge_request(
+        key: &[u8; KEY_SIZE],
+        tamper: impl FnOnce(&mut [u8; TAG_SIZE]),
+    ) -> (Result<()>, bool) {
+        let bus = MockBus::new();
+        let mut i2c = bus.attach(ADDRESS);
+        let slave = thread::spawn(move || {
+            let mut clock = MockClock::new();
+            let mut serviced = false;
+            loop {
+                match secure_slave_transaction(
+                    &mut i2c,
+                    &mut MockCipher::new(),
+                    &mut MockRng::new(2),
+                    &mut clock,
+                    &KEY,
+                    |_| {
+                        serviced = true;
+                        response()
+                    },
+                ) {
+                    Err(ErrorKind::NoneAvailable) => (),
+                    result => break (result, serviced),
+                }
+            }
+        });
+
+        let mut master = bus.master();
+        let master_nonce = random_nonce(&mut MockRng::new(1));
+        let challenge = master_nonce.into_iter().chain(Versions::OURS.encode());
+        master_send(&mut master, ADDRESS, challenge).unwrap();
+        let mut answer = [0u8; ANSWER_FRAME_SIZE];
+        master
+            .master_transaction(ADDRESS, Some(&mut answer), None)
+            .unwrap();
+
+        let mut session = Session {
+            master_nonce,
+            slave_nonce: [0u8; NONCE_SIZE],
+            version: MAX_VERSION,
+        };
+        session.slave_nonce.copy_from_slice(&answer[..NONCE_SIZE]);
+        let mut aes = MockCipher::new();
+        aes.set_key(key);
+        let mut proof = prove(
+            &mut aes,
+            MASTER_PROOF,
+            &session.slave_nonce,
+            &session.master_nonce,
+        );
+        tamper(&mut proof);
+        let mut channel = channel(&ListRequest, &session);
+        let (header, body) = channel.split_at_mut(HEADER_SIZE);
+        let tag = eax::seal(&mut aes, &frame_nonce(TO_SLAVE, header), header, body);
+        let frame = proof.iter().chain(&channel).chain(&tag).copied();
+        master_send(&mut master, ADDRESS, frame).unwrap();
+        // Only a slave that took the request answers.
+        let mut response = [0u8; RESPONSE_FRAME_SIZE];
+        _ = master.master_transaction(ADDRESS, Some(&mut response), None);
+
+        drop(bus);
+        slave.join().unwrap()
+    }
+
+    #[test]
+    fn test_forged_request_with_key_is_serviced() {
+        assert!(matches!(forge_request(&KEY, |_| ()), (Ok(()), true)));
+    }
+
+    #[test]
+    fn test_master_with_wrong_key_is_rejected() {
+        assert!(matches!(
+            forge_request(&[0x43; KEY_SIZE], |_| ()),
+            (Err(ErrorKind::Invalid), false)
+        ));
+    }
+
+    #[test]
+    fn test_tampered_master_proof_is_rejected() {
+        assert!(matches!(
+            forge_request(&KEY, |proof| proof[0] ^= 1),
+            (Err(ErrorKind::Invalid), false)
+        ));
+    }
+
+    #[test]
+    fn test_slave_waits_for_request_past_inter_byte_timeout() {
+        // Nothing of the request is under way yet, so only the frame timeout
+        // applies to the master taking its time.
+        let pause = Duration::from_micros(2 * INTER_BYTE_TIMEOUT_US);
+        assert!(pause < Duration::from_micros(FRAME_TIMEOUT_US));
+        assert!(matches!(
+            forge_request(&KEY, |_| thread::sleep(pause)),
+            (Ok(()), true)
+        ));
+    }
+
+    #[test]
+    fn test_slave_recovers_from_partial_frame() {
+        let bus = MockBus::new();
+        let mut i2c = bus.attach(ADDRESS);
+        let slave = thread::spawn(move || {
+            let mut trng = MockRng::new(2);
+            (
+                serve(&mut i2c, &mut trng, &KEY),
+                serve(&mut i2c, &mut trng, &KEY),
+            )
+        });
+
+        // Half a challenge, then nothing until the slave gave up on it.
+        let mut master = bus.master();
+        master
+            .master_transaction(ADDRESS, None, Some(&[0; NONCE_SIZE / 2]))
+            .unwrap();
+        thread::sleep(Duration::from_micros(
+            FRAME_TIMEOUT_US + 2 * INTER_BYTE_TIMEOUT_US,
+        ));
+
+        let response = secure_master_transaction(
+            &mut master,
+            &mut MockCipher::new(),
+            &mut MockRng::new(1),
+            ADDRESS,
+            &KEY,
+            &ListRequest,
+        );
+        drop(bus);
+        let (partial, serviced) = slave.join().unwrap();
+
+        assert!(matches!(partial, Err(ErrorKind::TimeOut)));
+        assert!(response.is_ok());
+        assert!(matches!(serviced, Ok(Some(TransactionKind::List(_)))));
+    }
+
+    /// Flips a bit in the `nth` frame the slave sends, counting from 0.
+    struct CorruptingSlave {
+        i2c: MockSlave,
+        sent: usize,
+        nth: usize,
+    }
+
+    impl I2cSlave for CorruptingSlave {
+        fn slave_receive(&mut self, rx: &mut [u8]) -> Result<usize> {
+            self.i2c.slave_receive(rx)
+        }
+
+        fn slave_send(&mut self, tx: &mut dyn Iterator<Item = u8>) -> Result<()> {
+            let corrupt = self.sent == self.nth;
+            let mut tx = tx
+                .enumerate()
+                .map(|(i, byte)| if corrupt && i == 0 { byte ^ 1 } else { byte });
+            self.i2c.slave_send(&mut tx)?;
+            self.sent += 1;
+            Ok(())
+        }
+    }
+
+    /// Runs `request` under retries while the slave's first response gets
+    /// corrupted, and returns what the master got and how many requests the
+    /// slave serviced.
+    fn transact_corrupted<Req: Request>(
+        request: Req,
+    ) -> (Retried<Req::Response, MasterError>, usize) {
+        let bus = MockBus::new();
+        // The answer to the challenge is frame 0, the response frame 1.
+        let mut i2c = CorruptingSlave {
+            i2c: bus.attach(ADDRESS),
+            sent: 0,
+            nth: 1,
+        };
+        let slave = thread::spawn(move || {
+            let mut serviced = 0;
+            let mut trng = MockRng::new(2);
+            while let Ok(kind) = serve(&mut i2c, &mut trng, &KEY) {
+                serviced += kind.is_some() as usize;
+            }
+            serviced
+        });
+
+        let retried = retried_master_transaction(
+            &mut bus.master(),
+            &mut MockCipher::new(),
+            &mut MockRng::new(1),
+            &mut MockClock::new(),
+            ADDRESS,
+            &KEY,
+            &request,
+        );
+        drop(bus);
+        (retried, slave.join().unwrap())
+    }
+
+    #[test]
+    fn test_repeatable_request_is_retried_after_lost_response() {
+        let (retried, serviced) = transact_corrupted(ListRequest);
+        assert!(matches!(
+            retried,
+            Retried {
+                result: Ok(_),
+                retries: 1
+            }
+        ));
+        assert_eq!(serviced, 2);
+    }
+
+    #[test]
+    fn test_request_is_not_repeated_after_lost_response() {
+        let (retried, serviced) = transact_corrupted(RawPayload::new(b"once").unwrap());
+        assert!(matches!(
+            retried,
+            Retried {
+                result: Err(MasterError::Other(ErrorKind::ComError)),
+                retries: 0
+            }
+        ));
+        assert_eq!(serviced, 1);
+
+        let (retried, serviced) = transact_corrupted(BootRequest);
+        assert!(matches!(
+            retried.result,
+            Err(MasterError::Other(ErrorKind::ComError))
+        ));
+        assert_eq!(serviced, 1);
+    }
+
+    #[test]
+    fn test_missing_slave_is_reported() {
+        let bus = MockBus::new();
+        assert!(matches!(
+            secure_master_transaction(
+                &mut bus.master(),
+                &mut MockCipher::new(),
+                &mut MockRng::new(1),
+                ADDRESS,
+                &KEY,
+                &ListRequest,
+            ),
+            Err(ErrorKind::ComError)
+        ));
     }
 }
@@ -0,0 +1,38 @@
+#!/usr/bin/env python3
+"""Generate a fresh deployment secrets file for `build.rs` (see `ECTF_SECRETS`).
+
+Run once per deployment. Every AP and component of the deployment must be built
+from the same file, so keep it outside the source tree and never commit it.
+"""
+
+import argparse
+import secrets
+import sys
+from pathlib import Path
+
+DEPLOYMENT_SECRET_SIZE = 16
+
+
+def main() -> int:
+    parser = argparse.ArgumentParser(description=__doc__)
+    parser.add_argument("path", type=Path, help="where to write the secrets file")
+    parser.add_argument(
+        "--force", action="store_true", help="overwrite an existing secrets file"
+    )
+    args = parser.parse_args()
+
+    if args.path.exists() and not args.force:
+        print(f"{args.path} already exists, pass --force to replace it", file=sys.stderr)
+        return 1
+
+    args.path.parent.mkdir(parents=True, exist_ok=True)
+    args.path.write_text(
+        "# Generated by tools/gen_secrets.py, do not commit.\n"
+        f"deployment_secret = {secrets.token_hex(DEPLOYMENT_SECRET_SIZE)}\n"
+    )
+    args.path.chmod(0o600)
+    return 0
+
+
+if __name__ == "__main__":
+    sys.exit(main())